@@ -1,24 +1,62 @@
 use std::fs::File;
-use std::io::Read;
+use std::io::{Read, Write};
 use std::rc::Rc;
 use std::sync::Mutex;
 use std::time::{SystemTime, UNIX_EPOCH};
 
+use num_bigint::BigInt as BigInteger;
+use num_traits::{FromPrimitive, ToPrimitive};
+
 extern crate rustyline;
 use rustyline::error::ReadlineError;
 use rustyline::Editor;
 
 use crate::printer::pr_seq;
-use crate::reader::read_str;
+use crate::reader::{read_all, read_str, skip_shebang};
 use crate::types::MalErr::ErrMalVal;
-use crate::types::MalVal::{Atom, Bool, Func, Hash, Int, List, MalFunc, Nil, Str, Sym, Vector};
-use crate::types::{MalArgs, MalRet, MalVal, _assoc, _dissoc, atom, error, func, hash_map};
-
-macro_rules! fn_t_int_int {
-    ($ret:ident, $fn:expr) => {{
-        |a: MalArgs| match (a[0].clone(), a[1].clone()) {
-            (Int(a0), Int(a1)) => Ok($ret($fn(a0, a1))),
-            _ => error("expecting (int,int) args"),
+use crate::types::MalVal::{
+    Atom, BigInt, Bool, Char, ExInfo, Float, Func, Hash, Int, Keyword, List, MalFunc, Nil, Set,
+    Str, Sym, Vector,
+};
+use crate::types::{
+    Frame, MalArgs, MalErr, MalMap, MalRet, MalSeq, MalVal, _assoc, _conj_set, _disj, _dissoc,
+    atom, big_int, err_info, error, func, hash_key, hash_map, hash_set, keyword, type_error,
+    wrong_args,
+};
+
+// Arithmetic on two Ints is checked and promotes to BigInt on overflow.
+// If either arg is a Float both are converted to Float.
+macro_rules! fn_t_num_num {
+    ($checked:ident, $op:tt) => {{
+        |a0: &MalVal, a1: &MalVal| match (a0, a1) {
+            (Int(i0), Int(i1)) => Ok(match i0.$checked(*i1) {
+                Some(i) => Int(i),
+                None => big_int(BigInteger::from(*i0) $op BigInteger::from(*i1)),
+            }),
+            (Float(_), _) | (_, Float(_)) => match (to_float(a0), to_float(a1)) {
+                (Some(f0), Some(f1)) => Ok(Float(f0 $op f1)),
+                _ => type_error("expecting (number,number) args"),
+            },
+            _ => match (to_big(a0), to_big(a1)) {
+                (Some(b0), Some(b1)) => Ok(big_int(b0 $op b1)),
+                _ => type_error("expecting (number,number) args"),
+            },
+        }
+    }};
+}
+
+macro_rules! fn_t_num_cmp {
+    ($op:tt) => {{
+        |a0: &MalVal, a1: &MalVal| match (a0, a1) {
+            (Int(i0), Int(i1)) => Ok(i0 $op i1),
+            (Float(_), _) | (_, Float(_)) => match (to_float(a0), to_float(a1)) {
+                (Some(f0), Some(f1)) => Ok(f0 $op f1),
+                _ => Err(err_info("type-error", "expecting (number,number) args", &[])),
+            },
+            _ => match (to_big(a0), to_big(a1)) {
+                (Some(b0), Some(b1)) => Ok(b0 $op b1),
+                _ => Err(err_info("type-error", "expecting (number,number) args", &[])),
+            },
         }
     }};
 }
@@ -39,15 +77,113 @@ macro_rules! fn_str {
     ($fn:expr) => {{
         |a: MalArgs| match a[0].clone() {
             Str(a0) => $fn(a0),
-            _ => error("expecting (str) arg"),
+            _ => type_error("expecting (str) arg"),
+        }
+    }};
+}
+
+fn to_float(mv: &MalVal) -> Option<f64> {
+    match *mv {
+        Int(i) => Some(i as f64),
+        BigInt(ref b) => b.to_f64(),
+        Float(f) => Some(f),
+        _ => None,
+    }
+}
+
+fn to_big(mv: &MalVal) -> Option<BigInteger> {
+    match *mv {
+        Int(i) => Some(BigInteger::from(i)),
+        BigInt(ref b) => Some((**b).clone()),
+        _ => None,
+    }
+}
+
+// Checks that a builtin is called with exactly `$n` args (or at least
+// `$min` args for min_arity)
+macro_rules! arity {
+    ($name:expr, $n:expr, $f:expr) => {{
+        |a: MalArgs| {
+            let f: fn(MalArgs) -> MalRet = $f;
+            if a.len() != $n {
+                return arity_error(&a, $name);
+            }
+            f(a)
+        }
+    }};
+}
+
+macro_rules! min_arity {
+    ($name:expr, $min:expr, $f:expr) => {{
+        |a: MalArgs| {
+            let f: fn(MalArgs) -> MalRet = $f;
+            if a.len() < $min {
+                return arity_error(&a, $name);
+            }
+            f(a)
         }
     }};
 }
 
+fn arity_error(a: &MalArgs, name: &str) -> MalRet {
+    Err(wrong_args(a.len(), name))
+}
+
+// Left fold of a numeric operator over its args. With a single arg x the
+// result is (op unit x), so (- x) negates, and (op) is unit when
+// `nullary` is set.
+fn fold_num(
+    a: MalArgs,
+    name: &str,
+    unit: i64,
+    nullary: bool,
+    op: fn(&MalVal, &MalVal) -> MalRet,
+) -> MalRet {
+    match a.len() {
+        0 if nullary => Ok(Int(unit)),
+        0 => arity_error(&a, name),
+        1 => op(&Int(unit), &a[0]),
+        _ => {
+            let mut acc = a[0].clone();
+            for mv in a[1..].iter() {
+                acc = op(&acc, mv)?;
+            }
+            Ok(acc)
+        }
+    }
+}
+
+fn divide(a0: &MalVal, a1: &MalVal) -> MalRet {
+    let div = fn_t_num_num!(checked_div, /);
+    match (a0, a1) {
+        (Int(_), Int(0)) | (BigInt(_), Int(0)) => {
+            Err(err_info("arithmetic", "divide by zero", &[]))
+        }
+        _ => div(a0, a1),
+    }
+}
+
+// True when `op` holds for every adjacent pair of args
+fn chain_cmp(
+    a: MalArgs,
+    name: &str,
+    op: fn(&MalVal, &MalVal) -> Result<bool, MalErr>,
+) -> MalRet {
+    if a.is_empty() {
+        return arity_error(&a, name);
+    }
+    for w in a.windows(2) {
+        if !op(&w[0], &w[1])? {
+            return Ok(Bool(false));
+        }
+    }
+    Ok(Bool(true))
+}
+
 fn symbol(a: MalArgs) -> MalRet {
     match a[0] {
         Str(ref s) => Ok(Sym(s.to_string())),
-        _ => error("illegal symbol call"),
+        _ => type_error("illegal symbol call"),
     }
 }
 
@@ -75,18 +211,44 @@ fn readline(a: MalArgs) -> MalRet {
                 Err(e) => error(&format!("{:?}", e)),
             }
         }
-        _ => error("readline: prompt is not Str"),
+        _ => type_error("readline: prompt is not Str"),
     }
 }
 
+fn io_error(e: std::io::Error, file: &str) -> MalErr {
+    err_info("io", &e.to_string(), &[("file", Str(file.to_string()))])
+}
+
 fn slurp(f: String) -> MalRet {
     let mut s = String::new();
-    match File::open(f).and_then(|mut f| f.read_to_string(&mut s)) {
+    match File::open(&f).and_then(|mut fh| fh.read_to_string(&mut s)) {
         Ok(_) => Ok(Str(s)),
-        Err(e) => error(&e.to_string()),
+        Err(e) => Err(io_error(e, &f)),
+    }
+}
+
+// The list of forms in the file `f`, skipping any shebang line
+pub fn load_forms(f: String) -> MalRet {
+    let mut s = String::new();
+    match File::open(&f).and_then(|mut fh| fh.read_to_string(&mut s)) {
+        Ok(_) => read_all(skip_shebang(&s).to_string(), Some(&f)),
+        Err(e) => Err(io_error(e, &f)),
     }
 }
 
+fn exit(a: MalArgs) -> MalRet {
+    if a.len() > 1 {
+        return arity_error(&a, "exit");
+    }
+    let code = match a.first() {
+        None => 0,
+        Some(Int(code)) if (0..=255).contains(code) => *code as i32,
+        _ => return type_error("exit: expecting an exit code from 0 to 255"),
+    };
+    let _ = std::io::stdout().flush();
+    std::process::exit(code)
+}
+
 fn time_ms(_a: MalArgs) -> MalRet {
     let ms_e = match SystemTime::now().duration_since(UNIX_EPOCH) {
         Ok(d) => d,
@@ -100,110 +262,121 @@ fn time_ms(_a: MalArgs) -> MalRet {
 fn get(a: MalArgs) -> MalRet {
     match (a[0].clone(), a[1].clone()) {
         (Nil, _) => Ok(Nil),
-        (Hash(ref hm, _), Str(ref s)) => match hm.get(s) {
+        (Hash(ref hm, _), ref k) | (Set(ref hm, _), ref k) => match hm.get(&hash_key(k)?) {
             Some(mv) => Ok(mv.clone()),
             None => Ok(Nil),
         },
-        _ => error("illegal get args"),
+        _ => type_error("illegal get args"),
     }
 }
 
 fn assoc(a: MalArgs) -> MalRet {
     match a[0] {
-        Hash(ref hm, _) => _assoc((**hm).clone(), a[1..].to_vec()),
-        _ => error("assoc on non-Hash Map"),
+        Hash(ref hm, _) => _assoc(hm.clone(), &a[1..]),
+        _ => type_error("assoc on non-Hash Map"),
     }
 }
 
 fn dissoc(a: MalArgs) -> MalRet {
     match a[0] {
-        Hash(ref hm, _) => _dissoc((**hm).clone(), a[1..].to_vec()),
-        _ => error("dissoc on non-Hash Map"),
+        Hash(ref hm, _) => _dissoc(hm.clone(), &a[1..]),
+        _ => type_error("dissoc on non-Hash Map"),
     }
 }
 
 fn contains_q(a: MalArgs) -> MalRet {
     match (a[0].clone(), a[1].clone()) {
-        (Hash(ref hm, _), Str(ref s)) => Ok(Bool(hm.contains_key(s))),
-        _ => error("illegal get args"),
+        (Hash(ref hm, _), ref k) | (Set(ref hm, _), ref k) => {
+            Ok(Bool(hm.contains_key(&hash_key(k)?)))
+        }
+        _ => type_error("illegal get args"),
     }
 }
 
 fn keys(a: MalArgs) -> MalRet {
     match a[0] {
-        Hash(ref hm, _) => Ok(list!(hm.keys().map(|k| { Str(k.to_string()) }).collect())),
-        _ => error("keys requires Hash Map"),
+        Hash(ref hm, _) => Ok(list!(hm.keys().map(|k| k.to_mal()).collect())),
+        _ => type_error("keys requires Hash Map"),
     }
 }
 
 fn vals(a: MalArgs) -> MalRet {
     match a[0] {
-        Hash(ref hm, _) => Ok(list!(hm.values().map(|v| { v.clone() }).collect())),
-        _ => error("keys requires Hash Map"),
+        Hash(ref hm, _) => Ok(list!(hm.values().cloned().collect())),
+        _ => type_error("keys requires Hash Map"),
     }
 }
 
 fn vec(a: MalArgs) -> MalRet {
     match a[0] {
-        List(ref v, _) | Vector(ref v, _) => Ok(vector!(v.to_vec())),
-        _ => error("non-seq passed to vec"),
+        List(ref v, _) | Vector(ref v, _) => Ok(vector!(v.clone())),
+        _ => type_error("non-seq passed to vec"),
     }
 }
 
 fn cons(a: MalArgs) -> MalRet {
     match a[1].clone() {
-        List(v, _) | Vector(v, _) => {
-            let mut new_v = vec![a[0].clone()];
-            new_v.extend_from_slice(&v);
-            Ok(list!(new_v.to_vec()))
+        List(mut v, _) | Vector(mut v, _) => {
+            v.push_front(a[0].clone());
+            Ok(list!(v))
         }
-        _ => error("cons expects seq as second arg"),
+        _ => type_error("cons expects seq as second arg"),
     }
 }
 
 fn concat(a: MalArgs) -> MalRet {
-    let mut new_v = vec![];
+    let mut new_v = MalSeq::new();
     for seq in a.iter() {
         match seq {
-            List(v, _) | Vector(v, _) => new_v.extend_from_slice(v),
-            _ => return error("non-seq passed to concat"),
+            List(v, _) | Vector(v, _) => new_v.append(v.clone()),
+            _ => return type_error("non-seq passed to concat"),
         }
     }
-    Ok(list!(new_v.to_vec()))
+    Ok(list!(new_v))
+}
+
+fn index_error(idx: i64, count: usize) -> MalRet {
+    let context = [("index", Int(idx)), ("count", Int(count as i64))];
+    Err(err_info("index-out-of-bounds", "nth: index out of range", &context))
 }
 
 fn nth(a: MalArgs) -> MalRet {
-    match (a[0].clone(), a[1].clone()) {
-        (List(seq, _), Int(idx)) | (Vector(seq, _), Int(idx)) => {
+    match (&a[0], &a[1]) {
+        (List(seq, _), &Int(idx)) | (Vector(seq, _), &Int(idx)) => {
             if seq.len() <= idx as usize {
-                return error("nth: index out of range");
+                return index_error(idx, seq.len());
             }
             Ok(seq[idx as usize].clone())
         }
-        _ => error("invalid args to nth"),
+        (Str(s), &Int(idx)) => match s.chars().nth(idx as usize) {
+            Some(c) => Ok(Char(c)),
+            None => index_error(idx, s.chars().count()),
+        },
+        _ => type_error("invalid args to nth"),
     }
 }
 
 fn first(a: MalArgs) -> MalRet {
-    match a[0].clone() {
+    match a[0] {
         List(ref seq, _) | Vector(ref seq, _) if seq.len() == 0 => Ok(Nil),
         List(ref seq, _) | Vector(ref seq, _) => Ok(seq[0].clone()),
+        Str(ref s) => Ok(s.chars().next().map(Char).unwrap_or(Nil)),
         Nil => Ok(Nil),
-        _ => error("invalid args to first"),
+        _ => type_error("invalid args to first"),
     }
 }
 
 fn rest(a: MalArgs) -> MalRet {
-    match a[0].clone() {
+    match a[0] {
         List(ref seq, _) | Vector(ref seq, _) => {
             if seq.len() > 1 {
-                Ok(list!(seq[1..].to_vec()))
+                Ok(list!(seq.skip(1)))
             } else {
                 Ok(list![])
             }
         }
         Nil => Ok(list![]),
-        _ => error("invalid args to first"),
+        _ => type_error("invalid args to first"),
     }
 }
 
@@ -212,80 +385,248 @@ fn apply(a: MalArgs) -> MalRet {
         List(ref v, _) | Vector(ref v, _) => {
             let f = &a[0];
             let mut fargs = a[1..a.len() - 1].to_vec();
-            fargs.extend_from_slice(&v);
+            fargs.extend(v.iter().cloned());
             f.apply(fargs)
         }
-        _ => error("apply called with non-seq"),
+        _ => type_error("apply called with non-seq"),
     }
 }
 
 fn map(a: MalArgs) -> MalRet {
     match a[1] {
         List(ref v, _) | Vector(ref v, _) => {
-            let mut res = vec![];
+            let mut res = MalSeq::new();
             for mv in v.iter() {
-                res.push(a[0].apply(vec![mv.clone()])?)
+                res.push_back(a[0].apply(vec![mv.clone()])?)
             }
             Ok(list!(res))
         }
-        _ => error("map called with non-seq"),
+        _ => type_error("map called with non-seq"),
     }
 }
 
 fn conj(a: MalArgs) -> MalRet {
     match a[0] {
         List(ref v, _) => {
-            let sl = a[1..]
-                .iter()
-                .rev()
-                .map(|a| a.clone())
-                .collect::<Vec<MalVal>>();
-            Ok(list!([&sl[..], v].concat()))
+            let mut v = v.clone();
+            for mv in a[1..].iter() {
+                v.push_front(mv.clone());
+            }
+            Ok(list!(v))
+        }
+        Vector(ref v, _) => {
+            let mut v = v.clone();
+            v.extend(a[1..].iter().cloned());
+            Ok(vector!(v))
         }
-        Vector(ref v, _) => Ok(vector!([v, &a[1..]].concat())),
-        _ => error("conj: called with non-seq"),
+        Set(ref hs, _) => _conj_set(hs.clone(), &a[1..]),
+        _ => type_error("conj: called with non-seq"),
+    }
+}
+
+fn set(a: MalArgs) -> MalRet {
+    match a[0] {
+        List(ref v, _) | Vector(ref v, _) => {
+            let items: Vec<MalVal> = v.iter().cloned().collect();
+            hash_set(&items)
+        }
+        Set(ref hs, _) => Ok(Set(hs.clone(), Rc::default())),
+        Nil => hash_set(&[]),
+        _ => type_error("set: called with non-seq"),
+    }
+}
+
+fn disj(a: MalArgs) -> MalRet {
+    match a[0] {
+        Set(ref hs, _) => _disj(hs.clone(), &a[1..]),
+        _ => type_error("disj on non-Set"),
+    }
+}
+
+// The args of union, intersection and difference, which must all be sets.
+fn set_args<'a>(a: &'a MalArgs, name: &str) -> Result<Vec<&'a MalMap>, MalErr> {
+    a.iter()
+        .map(|mv| match mv {
+            Set(hs, _) => Ok(hs),
+            _ => Err(err_info("type-error", &format!("{}: called with non-set", name), &[])),
+        })
+        .collect()
+}
+
+fn union(a: MalArgs) -> MalRet {
+    let sets = set_args(&a, "union")?;
+    let mut res = sets[0].clone();
+    for hs in sets[1..].iter() {
+        for (k, mv) in hs.iter() {
+            res.insert(k.clone(), mv.clone());
+        }
+    }
+    Ok(Set(res, Rc::default()))
+}
+
+fn intersection(a: MalArgs) -> MalRet {
+    let sets = set_args(&a, "intersection")?;
+    let mut res = sets[0].clone();
+    for k in sets[0].keys() {
+        if !sets[1..].iter().all(|hs| hs.contains_key(k)) {
+            res.remove(k);
+        }
+    }
+    Ok(Set(res, Rc::default()))
+}
+
+fn difference(a: MalArgs) -> MalRet {
+    let sets = set_args(&a, "difference")?;
+    let mut res = sets[0].clone();
+    for hs in sets[1..].iter() {
+        for k in hs.keys() {
+            res.remove(k);
+        }
+    }
+    Ok(Set(res, Rc::default()))
+}
+
+fn char(a: MalArgs) -> MalRet {
+    match a[0] {
+        Int(i) if i >= 0 && i <= u32::MAX as i64 => match std::char::from_u32(i as u32) {
+            Some(c) => Ok(Char(c)),
+            None => error(&format!("char: invalid code point {}", i)),
+        },
+        Char(c) => Ok(Char(c)),
+        _ => type_error(&format!("char: invalid code point {}", a[0].pr_str(true))),
+    }
+}
+
+fn int(a: MalArgs) -> MalRet {
+    match a[0] {
+        Char(c) => Ok(Int(c as i64)),
+        Int(_) | BigInt(_) => Ok(a[0].clone()),
+        Float(f) => match BigInteger::from_f64(f.trunc()) {
+            Some(b) => Ok(big_int(b)),
+            None => error(&format!("int: cannot convert {}", a[0].pr_str(true))),
+        },
+        _ => type_error(&format!("int: cannot convert {}", a[0].pr_str(true))),
     }
 }
 
 fn seq(a: MalArgs) -> MalRet {
     match a[0] {
         List(ref v, _) | Vector(ref v, _) if v.len() == 0 => Ok(Nil),
-        List(ref v, _) | Vector(ref v, _) => Ok(list!(v.to_vec())),
+        List(ref v, _) | Vector(ref v, _) => Ok(list!(v.clone())),
+        Set(ref hs, _) if hs.len() == 0 => Ok(Nil),
+        Set(ref hs, _) => Ok(list!(hs.values().cloned().collect())),
         Str(ref s) if s.len() == 0 => Ok(Nil),
-        Str(ref s) if !a[0].keyword_q() => {
-            Ok(list!(s.chars().map(|c| { Str(c.to_string()) }).collect()))
-        }
+        Str(ref s) => Ok(list!(s.chars().map(Char).collect())),
         Nil => Ok(Nil),
-        _ => error("seq: called with non-seq"),
+        _ => type_error("seq: called with non-seq"),
+    }
+}
+
+// The calls that the error a value was caught from propagated out of,
+// innermost first. Nil for a value not bound by catch*, or one that
+// can't keep its error, like a thrown number or string.
+fn ex_trace(a: MalArgs) -> MalRet {
+    Ok(match a[0].caught() {
+        Some(e) => vector!(e.trace().iter().map(Frame::to_mal).collect()),
+        None => Nil,
+    })
+}
+
+// Throwing a value bound by catch* rethrows the error it was caught from,
+// which keeps its position and stack trace
+fn throw(a: MalArgs) -> MalRet {
+    Err(match a[0].caught() {
+        Some(e) => e.clone(),
+        None => ErrMalVal(a[0].clone()),
+    })
+}
+
+// (ex-info msg data) keeps msg apart from data, so data may have keys of
+// its own named :message or :type. A catch* keyword selector matches the
+// :type in data.
+fn ex_info(a: MalArgs) -> MalRet {
+    if a.is_empty() || a.len() > 2 {
+        return arity_error(&a, "ex-info");
+    }
+    let message = match a[0] {
+        Str(ref msg) => msg.clone(),
+        _ => return type_error("ex-info: message must be a string"),
+    };
+    let data = match a.get(1) {
+        None | Some(Nil) => hash_map(&[])?,
+        Some(data @ Hash(..)) => data.clone(),
+        Some(_) => return type_error("ex-info: data must be a map"),
+    };
+    Ok(ExInfo {
+        message,
+        data: Rc::new(data),
+        meta: Rc::default(),
+    })
+}
+
+// The data of an ex-info, or the entries of an interpreter error map other
+// than its :message. Nil for anything else.
+fn ex_data(a: MalArgs) -> MalRet {
+    match a[0] {
+        ExInfo { ref data, .. } => Ok((**data).clone()),
+        Hash(ref hm, _) if hm.contains_key(&hash_key(&keyword("message"))?) => {
+            _dissoc(hm.clone(), &[keyword("message")])
+        }
+        _ => Ok(Nil),
+    }
+}
+
+// The message of an ex-info or an interpreter error map, or a thrown
+// string itself
+fn ex_message(a: MalArgs) -> MalRet {
+    match a[0] {
+        ExInfo { ref message, .. } => Ok(Str(message.clone())),
+        Hash(ref hm, _) => Ok(hm.get(&hash_key(&keyword("message"))?).cloned().unwrap_or(Nil)),
+        Str(_) => Ok(a[0].clone()),
+        _ => Ok(Nil),
     }
 }
 
 pub fn ns() -> Vec<(&'static str, MalVal)> {
     vec![
-        ("=", func(|a| Ok(Bool(a[0] == a[1])))),
-        ("throw", func(|a| Err(ErrMalVal(a[0].clone())))),
-        ("nil?", func(fn_is_type!(Nil))),
-        ("true?", func(fn_is_type!(Bool(true)))),
-        ("false?", func(fn_is_type!(Bool(false)))),
-        ("symbol", func(symbol)),
-        ("symbol?", func(fn_is_type!(Sym(_)))),
-        (
-            "string?",
-            func(fn_is_type!(Str(ref s) if !s.starts_with("\u{29e}"))),
-        ),
-        ("keyword", func(|a| a[0].keyword())),
+        ("=", func(|a| chain_cmp(a, "=", |a0, a1| Ok(a0 == a1)))),
+        ("throw", func(arity!("throw", 1, throw))),
+        ("ex-trace", func(arity!("ex-trace", 1, ex_trace))),
+        ("ex-info", func(ex_info)),
+        ("ex-data", func(arity!("ex-data", 1, ex_data))),
+        ("ex-message", func(arity!("ex-message", 1, ex_message))),
+        ("nil?", func(arity!("nil?", 1, fn_is_type!(Nil)))),
+        ("true?", func(arity!("true?", 1, fn_is_type!(Bool(true))))),
+        ("false?", func(arity!("false?", 1, fn_is_type!(Bool(false))))),
+        ("symbol", func(arity!("symbol", 1, symbol))),
+        ("symbol?", func(arity!("symbol?", 1, fn_is_type!(Sym(_))))),
+        ("string?", func(arity!("string?", 1, fn_is_type!(Str(_))))),
+        ("keyword", func(arity!("keyword", 1, |a| a[0].keyword()))),
+        ("keyword?", func(arity!("keyword?", 1, fn_is_type!(Keyword(_))))),
+        ("char", func(arity!("char", 1, char))),
+        ("char?", func(arity!("char?", 1, fn_is_type!(Char(_))))),
+        ("int", func(arity!("int", 1, int))),
         (
-            "keyword?",
-            func(fn_is_type!(Str(ref s) if s.starts_with("\u{29e}"))),
+            "number?",
+            func(arity!("number?", 1, fn_is_type!(Int(_), BigInt(_), Float(_)))),
         ),
-        ("number?", func(fn_is_type!(Int(_)))),
+        ("int?", func(arity!("int?", 1, fn_is_type!(Int(_), BigInt(_))))),
+        ("float?", func(arity!("float?", 1, fn_is_type!(Float(_))))),
         (
             "fn?",
-            func(fn_is_type!(MalFunc{is_macro,..} if !is_macro,Func(_,_))),
+            func(arity!(
+                "fn?",
+                1,
+                fn_is_type!(MalFunc{is_macro,..} if !is_macro,Func(_,_))
+            )),
         ),
         (
             "macro?",
-            func(fn_is_type!(MalFunc{is_macro,..} if is_macro)),
+            func(arity!(
+                "macro?",
+                1,
+                fn_is_type!(MalFunc{is_macro,..} if is_macro)
+            )),
         ),
         ("pr-str", func(|a| Ok(Str(pr_seq(&a, true, "", "", " "))))),
         ("str", func(|a| Ok(Str(pr_seq(&a, false, "", "", ""))))),
@@ -303,49 +644,81 @@ pub fn ns() -> Vec<(&'static str, MalVal)> {
                 Ok(Nil)
             }),
         ),
-        ("read-string", func(fn_str!(|s| { read_str(s) }))),
-        ("readline", func(readline)),
-        ("slurp", func(fn_str!(|f| { slurp(f) }))),
-        ("<", func(fn_t_int_int!(Bool, |i, j| { i < j }))),
-        ("<=", func(fn_t_int_int!(Bool, |i, j| { i <= j }))),
-        (">", func(fn_t_int_int!(Bool, |i, j| { i > j }))),
-        (">=", func(fn_t_int_int!(Bool, |i, j| { i >= j }))),
-        ("+", func(fn_t_int_int!(Int, |i, j| { i + j }))),
-        ("-", func(fn_t_int_int!(Int, |i, j| { i - j }))),
-        ("*", func(fn_t_int_int!(Int, |i, j| { i * j }))),
-        ("/", func(fn_t_int_int!(Int, |i, j| { i / j }))),
-        ("time-ms", func(time_ms)),
-        ("sequential?", func(fn_is_type!(List(_, _), Vector(_, _)))),
-        ("list", func(|a| Ok(list!(a)))),
-        ("list?", func(fn_is_type!(List(_, _)))),
-        ("vector", func(|a| Ok(vector!(a)))),
-        ("vector?", func(fn_is_type!(Vector(_, _)))),
-        ("hash-map", func(|a| hash_map(a))),
-        ("map?", func(fn_is_type!(Hash(_, _)))),
-        ("assoc", func(assoc)),
-        ("dissoc", func(dissoc)),
-        ("get", func(get)),
-        ("contains?", func(contains_q)),
-        ("keys", func(keys)),
-        ("vals", func(vals)),
-        ("vec", func(vec)),
-        ("cons", func(cons)),
+        (
+            "read-string",
+            func(arity!("read-string", 1, fn_str!(|s| { read_str(s) }))),
+        ),
+        (
+            "read-string-all",
+            func(arity!(
+                "read-string-all",
+                1,
+                fn_str!(|s| { read_all(s, Some("<string>")) })
+            )),
+        ),
+        ("readline", func(arity!("readline", 1, readline))),
+        ("slurp", func(arity!("slurp", 1, fn_str!(|f| { slurp(f) })))),
+        (
+            "read-file",
+            func(arity!("read-file", 1, fn_str!(|f| { load_forms(f) }))),
+        ),
+        ("<", func(|a| chain_cmp(a, "<", fn_t_num_cmp!(<)))),
+        ("<=", func(|a| chain_cmp(a, "<=", fn_t_num_cmp!(<=)))),
+        (">", func(|a| chain_cmp(a, ">", fn_t_num_cmp!(>)))),
+        (">=", func(|a| chain_cmp(a, ">=", fn_t_num_cmp!(>=)))),
+        ("+", func(|a| fold_num(a, "+", 0, true, fn_t_num_num!(checked_add, +)))),
+        ("-", func(|a| fold_num(a, "-", 0, false, fn_t_num_num!(checked_sub, -)))),
+        ("*", func(|a| fold_num(a, "*", 1, true, fn_t_num_num!(checked_mul, *)))),
+        ("/", func(|a| fold_num(a, "/", 1, false, divide))),
+        ("time-ms", func(arity!("time-ms", 0, time_ms))),
+        ("exit", func(exit)),
+        (
+            "sequential?",
+            func(arity!("sequential?", 1, fn_is_type!(List(_, _), Vector(_, _)))),
+        ),
+        ("list", func(|a| Ok(list!(a.into())))),
+        ("list?", func(arity!("list?", 1, fn_is_type!(List(_, _))))),
+        ("vector", func(|a| Ok(vector!(a.into())))),
+        ("vector?", func(arity!("vector?", 1, fn_is_type!(Vector(_, _))))),
+        ("hash-map", func(|a| hash_map(&a))),
+        ("map?", func(arity!("map?", 1, fn_is_type!(Hash(_, _))))),
+        ("assoc", func(min_arity!("assoc", 1, assoc))),
+        ("dissoc", func(min_arity!("dissoc", 1, dissoc))),
+        ("get", func(arity!("get", 2, get))),
+        ("contains?", func(arity!("contains?", 2, contains_q))),
+        ("keys", func(arity!("keys", 1, keys))),
+        ("vals", func(arity!("vals", 1, vals))),
+        ("hash-set", func(|a| hash_set(&a))),
+        ("set", func(arity!("set", 1, set))),
+        ("set?", func(arity!("set?", 1, fn_is_type!(Set(_, _))))),
+        ("disj", func(min_arity!("disj", 1, disj))),
+        ("union", func(min_arity!("union", 1, union))),
+        ("intersection", func(min_arity!("intersection", 1, intersection))),
+        ("difference", func(min_arity!("difference", 1, difference))),
+        ("vec", func(arity!("vec", 1, vec))),
+        ("cons", func(arity!("cons", 2, cons))),
         ("concat", func(concat)),
-        ("empty?", func(|a| a[0].empty_q())),
-        ("nth", func(nth)),
-        ("first", func(first)),
-        ("rest", func(rest)),
-        ("count", func(|a| a[0].count())),
-        ("apply", func(apply)),
-        ("map", func(map)),
-        ("conj", func(conj)),
-        ("seq", func(seq)),
-        ("meta", func(|a| a[0].get_meta())),
-        ("with-meta", func(|a| a[0].clone().with_meta(&a[1]))),
-        ("atom", func(|a| Ok(atom(&a[0])))),
-        ("atom?", func(fn_is_type!(Atom(_)))),
-        ("deref", func(|a| a[0].deref())),
-        ("reset!", func(|a| a[0].reset_bang(&a[1]))),
-        ("swap!", func(|a| a[0].swap_bang(&a[1..].to_vec()))),
+        ("empty?", func(arity!("empty?", 1, |a| a[0].empty_q()))),
+        ("nth", func(arity!("nth", 2, nth))),
+        ("first", func(arity!("first", 1, first))),
+        ("rest", func(arity!("rest", 1, rest))),
+        ("count", func(arity!("count", 1, |a| a[0].count()))),
+        ("apply", func(min_arity!("apply", 2, apply))),
+        ("map", func(arity!("map", 2, map))),
+        ("conj", func(min_arity!("conj", 1, conj))),
+        ("seq", func(arity!("seq", 1, seq))),
+        ("meta", func(arity!("meta", 1, |a| a[0].get_meta()))),
+        (
+            "with-meta",
+            func(arity!("with-meta", 2, |a| a[0].clone().with_meta(&a[1]))),
+        ),
+        ("atom", func(arity!("atom", 1, |a| Ok(atom(&a[0]))))),
+        ("atom?", func(arity!("atom?", 1, fn_is_type!(Atom(_))))),
+        ("deref", func(arity!("deref", 1, |a| a[0].deref()))),
+        ("reset!", func(arity!("reset!", 2, |a| a[0].reset_bang(&a[1])))),
+        (
+            "swap!",
+            func(min_arity!("swap!", 2, |a| a[0].swap_bang(&a[1..]))),
+        ),
     ]
 }
@@ -3,9 +3,11 @@ use std::rc::Rc;
 //use std::collections::HashMap;
 use fnv::FnvHashMap;
 
-use crate::types::MalErr::ErrString;
-use crate::types::MalVal::{List, Nil, Sym, Vector};
-use crate::types::{error, MalErr, MalRet, MalVal};
+use crate::types::MalVal::{Char, Hash, Keyword, List, Nil, Str, Sym, Vector};
+use crate::types::{
+    err_info, hash_key, hash_map, keyword, syntax_error, type_error, wrong_args, MalErr, MalMap,
+    MalRet, MalSeq, MalVal,
+};
 
 #[derive(Debug)]
 pub struct EnvStruct {
@@ -25,26 +27,204 @@ pub fn env_new(outer: Option<Env>) -> Env {
     })
 }
 
+// The evaluator, needed to evaluate the :or defaults of map patterns
+pub type EvalFn = fn(MalVal, Env) -> MalRet;
+
+// The number of fixed parameters in a function's binds, and whether it
+// takes any more arguments after them ('&')
+pub fn fn_arity(binds: &MalVal) -> Option<(usize, bool)> {
+    match binds {
+        List(ref b, _) | Vector(ref b, _) => {
+            Some(match b.iter().position(|b| matches!(b, Sym(s) if s == "&")) {
+                Some(i) => (i, true),
+                None => (b.len(), false),
+            })
+        }
+        _ => None,
+    }
+}
+
 // TODO: mbinds and exprs as & types
-pub fn env_bind(outer: Option<Env>, mbinds: MalVal, exprs: Vec<MalVal>) -> Result<Env, MalErr> {
+pub fn env_bind(
+    outer: Option<Env>,
+    mbinds: MalVal,
+    exprs: Vec<MalVal>,
+    eval: EvalFn,
+) -> Result<Env, MalErr> {
     let env = env_new(outer);
     match mbinds {
-        List(binds, _) | Vector(binds, _) => {
+        List(ref binds, _) | Vector(ref binds, _) => {
+            let arity_ok = match fn_arity(&mbinds) {
+                Some((n, true)) => exprs.len() >= n,
+                _ => exprs.len() == binds.len(),
+            };
+            if !arity_ok {
+                return Err(wrong_args(exprs.len(), &format!("fn {}", mbinds.pr_str(true))));
+            }
             for (i, b) in binds.iter().enumerate() {
                 match b {
-                    Sym(s) if s == "&" => {
-                        env_set(&env, binds[i + 1].clone(), list!(exprs[i..].to_vec()))?;
-                        break;
+                    Sym(s) if s == "&" => match binds.get(i + 1) {
+                        Some(rest) if i + 2 == binds.len() => {
+                            let rest_val = list!(exprs[i..].to_vec().into());
+                            env_destructure(&env, rest, rest_val, eval)?;
+                            break;
+                        }
+                        Some(_) => {
+                            return Err(syntax_error("'&' must be followed by one binding"))
+                        }
+                        None => return Err(syntax_error("missing symbol after '&'")),
+                    },
+                    _ => env_destructure(&env, b, exprs[i].clone(), eval)?,
+                }
+            }
+            Ok(env)
+        }
+        _ => Err(syntax_error("env_bind binds not List/Vector")),
+    }
+}
+
+// Bind the symbols in `pattern` to the matching parts of `val`. A symbol
+// binds the whole value, a list or vector binds the items of a sequence
+// ([a b & rest :as all]) and a hash-map looks up keys
+// ({a :a, :keys [b c], :or {c 0}, :as m}).
+pub fn env_destructure(
+    env: &Env,
+    pattern: &MalVal,
+    val: MalVal,
+    eval: EvalFn,
+) -> Result<(), MalErr> {
+    match pattern {
+        Sym(_) => {
+            env_set(env, pattern.clone(), val)?;
+            Ok(())
+        }
+        List(pats, _) | Vector(pats, _) => destructure_seq(env, pats, val, eval),
+        Hash(pats, _) => destructure_map(env, pats, val, eval),
+        _ => Err(syntax_error(&format!("invalid binding form {}", pattern.pr_str(true)))),
+    }
+}
+
+fn is_keyword(v: &MalVal, name: &str) -> bool {
+    matches!(v, Keyword(k) if &**k == name)
+}
+
+// The symbol following :as, which has to be the last binding
+fn as_binding(pats: &[MalVal]) -> Result<MalVal, MalErr> {
+    match pats {
+        [sym @ Sym(_)] => Ok(sym.clone()),
+        [_] | [] => Err(syntax_error(":as must be followed by a symbol")),
+        _ => Err(syntax_error(":as must come last in a binding form")),
+    }
+}
+
+fn destructure_seq(env: &Env, pats: &MalSeq, val: MalVal, eval: EvalFn) -> Result<(), MalErr> {
+    let items: Vec<MalVal> = match val {
+        List(ref l, _) | Vector(ref l, _) => l.iter().cloned().collect(),
+        Str(ref s) => s.chars().map(Char).collect(),
+        Nil => vec![],
+        _ => {
+            let msg = format!("cannot destructure {} as a sequence", val.pr_str(true));
+            return Err(err_info("type-error", &msg, &[]));
+        }
+    };
+    let pats: Vec<MalVal> = pats.iter().cloned().collect();
+    let mut i = 0;
+    while i < pats.len() {
+        match &pats[i] {
+            Sym(s) if s == "&" => {
+                let rest = match pats.get(i + 1) {
+                    Some(rest) if !is_keyword(rest, "as") => rest,
+                    _ => return Err(syntax_error("missing binding after '&'")),
+                };
+                let rest_val = list!(items.get(i..).unwrap_or_default().to_vec().into());
+                env_destructure(env, rest, rest_val, eval)?;
+                match pats.get(i + 2) {
+                    Some(k) if is_keyword(k, "as") => {}
+                    Some(_) => {
+                        return Err(syntax_error("'&' must be followed by one binding"))
+                    }
+                    None => {}
+                }
+                i += 2;
+            }
+            k if is_keyword(k, "as") => {
+                env_set(env, as_binding(&pats[i + 1..])?, val.clone())?;
+                i += 2;
+            }
+            p => {
+                env_destructure(env, p, items.get(i).cloned().unwrap_or(Nil), eval)?;
+                i += 1;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn destructure_map(env: &Env, pats: &MalMap, val: MalVal, eval: EvalFn) -> Result<(), MalErr> {
+    let map = match val {
+        Hash(ref m, _) => m.clone(),
+        Nil => MalMap::default(),
+        // the rest args of a function taking keyword arguments
+        List(ref l, _) | Vector(ref l, _) if l.len() % 2 == 0 => {
+            match hash_map(&l.iter().cloned().collect::<Vec<MalVal>>())? {
+                Hash(m, _) => m,
+                _ => unreachable!(),
+            }
+        }
+        _ => {
+            let msg = format!("cannot destructure {} as a map", val.pr_str(true));
+            return Err(err_info("type-error", &msg, &[]));
+        }
+    };
+    let defaults = match pats.get(&hash_key(&keyword("or"))?) {
+        Some(Hash(d, _)) => d.clone(),
+        Some(_) => return Err(syntax_error(":or must be followed by a map")),
+        None => MalMap::default(),
+    };
+    // bind `pattern` to the value at `key`, or to its :or default
+    let bind = |pattern: &MalVal, key: &MalVal| -> Result<(), MalErr> {
+        let v = match (map.get(&hash_key(key)?), pattern) {
+            (Some(v), _) => v.clone(),
+            (None, Sym(_)) => match defaults.get(&hash_key(pattern)?) {
+                Some(d) => eval(d.clone(), env.clone())?,
+                None => Nil,
+            },
+            (None, _) => Nil,
+        };
+        env_destructure(env, pattern, v, eval)
+    };
+    for (k, v) in pats.iter() {
+        match k.to_mal() {
+            Keyword(ref kind) if matches!(&**kind, "keys" | "strs" | "syms") => {
+                let syms = match v {
+                    List(syms, _) | Vector(syms, _) if syms.iter().all(|s| matches!(s, Sym(_))) => {
+                        syms
                     }
                     _ => {
-                        env_set(&env, b.clone(), exprs[i].clone())?;
+                        return Err(syntax_error(&format!(
+                            ":{} must be followed by a vector of symbols",
+                            kind
+                        )))
                     }
+                };
+                for sym in syms.iter() {
+                    let name = if let Sym(name) = sym { name } else { unreachable!() };
+                    let key = match &**kind {
+                        "keys" => keyword(name),
+                        "strs" => Str(name.clone()),
+                        _ => sym.clone(),
+                    };
+                    bind(sym, &key)?;
                 }
             }
-            Ok(env)
+            Keyword(ref kind) if &**kind == "or" => {}
+            Keyword(ref kind) if &**kind == "as" => {
+                env_set(env, as_binding(std::slice::from_ref(v))?, val.clone())?;
+            }
+            pattern => bind(&pattern, v)?,
         }
-        _ => Err(ErrString("env_bind binds not List/Vector".to_string())),
     }
+    Ok(())
 }
 
 pub fn env_find(env: &Env, key: &str) -> Option<Env> {
@@ -55,6 +235,10 @@ pub fn env_find(env: &Env, key: &str) -> Option<Env> {
     }
 }
 
+fn not_found(sym: &str) -> MalErr {
+    err_info("not-found", &format!("'{}' not found", sym), &[("symbol", Sym(sym.to_string()))])
+}
+
 pub fn env_get(env: &Env, key: &MalVal) -> MalRet {
     match key {
         Sym(ref s) => match env_find(env, s) {
@@ -62,11 +246,11 @@ pub fn env_get(env: &Env, key: &MalVal) -> MalRet {
                 .data
                 .borrow()
                 .get(s)
-                .ok_or(ErrString(format!("'{}' not found", s)))?
+                .ok_or_else(|| not_found(s))?
                 .clone()),
-            _ => error(&format!("'{}' not found", s)),
+            _ => Err(not_found(s)),
         },
-        _ => error("Env.get called with non-Str"),
+        _ => type_error("Env.get called with non-Str"),
     }
 }
 
@@ -76,10 +260,28 @@ pub fn env_set(env: &Env, key: MalVal, val: MalVal) -> MalRet {
             env.data.borrow_mut().insert(s.to_string(), val.clone());
             Ok(val)
         }
-        _ => error("Env.set called with non-Str"),
+        _ => type_error("Env.set called with non-Str"),
     }
 }
 
 pub fn env_sets(env: &Env, key: &str, val: MalVal) {
     env.data.borrow_mut().insert(key.to_string(), val);
 }
+
+// Like env_sets, but keeps any binding of `key` already in `env` itself
+pub fn env_sets_new(env: &Env, key: &str, val: MalVal) {
+    env.data.borrow_mut().entry(key.to_string()).or_insert(val);
+}
+
+// The names bound in `env` and all of its outer environments, sorted
+pub fn env_keys(env: &Env) -> Vec<String> {
+    let mut keys = vec![];
+    let mut env = Some(env.clone());
+    while let Some(e) = env {
+        keys.extend(e.data.borrow().keys().cloned());
+        env = e.outer.clone();
+    }
+    keys.sort();
+    keys.dedup();
+    keys
+}
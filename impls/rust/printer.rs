@@ -1,18 +1,51 @@
-use crate::types::MalVal;
-use crate::types::MalVal::{Atom, Bool, Func, Hash, Int, List, MalFunc, Nil, Str, Sym, Vector};
+use crate::types::{Arity, MalVal};
+use crate::types::MalVal::{
+    Atom, BigInt, Bool, Char, ExInfo, Float, Func, Hash, Int, Keyword, List, MalFunc, Nil, Set,
+    Str, Sym, Vector,
+};
 
 fn escape_str(s: &str) -> String {
     s.chars()
         .map(|c| match c {
             '"' => "\\\"".to_string(),
             '\n' => "\\n".to_string(),
+            '\t' => "\\t".to_string(),
+            '\r' => "\\r".to_string(),
+            '\0' => "\\0".to_string(),
             '\\' => "\\\\".to_string(),
+            c if c.is_control() => format!("\\u{:04x}", c as u32),
             _ => c.to_string(),
         })
         .collect::<Vec<String>>()
         .join("")
 }
 
+// Readable form of a character literal, see read_char
+fn pr_char(c: char) -> String {
+    match c {
+        '\n' => "\\newline".to_string(),
+        ' ' => "\\space".to_string(),
+        '\t' => "\\tab".to_string(),
+        '\r' => "\\return".to_string(),
+        '\u{8}' => "\\backspace".to_string(),
+        '\u{c}' => "\\formfeed".to_string(),
+        c if c.is_control() => format!("\\u{:04x}", c as u32),
+        c => format!("\\{}", c),
+    }
+}
+
+// Debug formatting gives the shortest representation that reads back as
+// the same value, and always includes a '.' or an exponent.
+fn pr_float(f: f64) -> String {
+    if f.is_nan() {
+        String::from("##NaN")
+    } else if f.is_infinite() {
+        String::from(if f > 0.0 { "##Inf" } else { "##-Inf" })
+    } else {
+        format!("{:?}", f)
+    }
+}
+
 impl MalVal {
     pub fn pr_str(&self, print_readably: bool) -> String {
         match self {
@@ -20,42 +53,66 @@ impl MalVal {
             Bool(true) => String::from("true"),
             Bool(false) => String::from("false"),
             Int(i) => format!("{}", i),
-            //Float(f)    => format!("{}", f),
+            BigInt(b) => format!("{}", b),
+            Float(f) => pr_float(*f),
             Str(s) => {
-                if s.starts_with("\u{29e}") {
-                    format!(":{}", &s[2..])
-                } else if print_readably {
+                if print_readably {
                     format!("\"{}\"", escape_str(s))
                 } else {
                     s.clone()
                 }
             }
+            Char(c) => {
+                if print_readably {
+                    pr_char(*c)
+                } else {
+                    c.to_string()
+                }
+            }
+            Keyword(k) => format!(":{}", k),
             Sym(s) => s.clone(),
-            List(l, _) => pr_seq(&**l, print_readably, "(", ")", " "),
-            Vector(l, _) => pr_seq(&**l, print_readably, "[", "]", " "),
+            List(l, _) => pr_seq(l, print_readably, "(", ")", " "),
+            Vector(l, _) => pr_seq(l, print_readably, "[", "]", " "),
             Hash(hm, _) => {
                 let l: Vec<MalVal> = hm
                     .iter()
-                    .flat_map(|(k, v)| vec![Str(k.to_string()), v.clone()])
+                    .flat_map(|(k, v)| vec![k.to_mal(), v.clone()])
                     .collect();
                 pr_seq(&l, print_readably, "{", "}", " ")
             }
+            Set(hs, _) => pr_seq(hs.values(), print_readably, "#{", "}", " "),
             Func(f, _) => format!("#<fn {:?}>", f),
-            MalFunc {
-                ast: a, params: p, ..
-            } => format!("(fn* {} {})", p.pr_str(true), a.pr_str(true)),
+            MalFunc { name, arities, .. } => pr_fn(name.as_deref(), arities),
             Atom(a) => format!("(atom {})", a.borrow().pr_str(true)),
+            ExInfo { message, data, .. } => {
+                format!("(ex-info {} {})", Str(message.clone()).pr_str(true), data.pr_str(true))
+            }
+        }
+    }
+}
+
+// The fn* form that creates a function: (fn* name? params body) when it
+// has one arity, else (fn* name? (params body)...)
+fn pr_fn(name: Option<&str>, arities: &[Arity]) -> String {
+    let mut parts: Vec<String> = name.map(String::from).into_iter().collect();
+    if let [(params, body)] = arities {
+        parts.push(params.pr_str(true));
+        parts.push(body.pr_str(true));
+    } else {
+        for (params, body) in arities {
+            parts.push(format!("({} {})", params.pr_str(true), body.pr_str(true)));
         }
     }
+    format!("(fn* {})", parts.join(" "))
 }
 
-pub fn pr_seq(
-    seq: &Vec<MalVal>,
+pub fn pr_seq<'a, I: IntoIterator<Item = &'a MalVal>>(
+    seq: I,
     print_readably: bool,
     start: &str,
     end: &str,
     join: &str,
 ) -> String {
-    let strs: Vec<String> = seq.iter().map(|x| x.pr_str(print_readably)).collect();
+    let strs: Vec<String> = seq.into_iter().map(|x| x.pr_str(print_readably)).collect();
     format!("{}{}{}", start, strs.join(join), end)
 }
@@ -1,81 +1,277 @@
-use regex::{Captures, Regex};
+use regex::Regex;
 use std::rc::Rc;
 
 use crate::types::MalErr::ErrString;
-use crate::types::MalVal::{Bool, Int, List, Nil, Str, Sym, Vector};
-use crate::types::{error, hash_map, MalErr, MalRet, MalVal};
+use crate::types::MalVal::{Bool, Char, Float, Int, List, Nil, Str, Sym, Vector};
+use crate::types::{
+    big_int, err_info, error, hash_map, hash_set, keyword, MalErr, MalRet, MalVal, SrcPos,
+};
 
-#[derive(Debug, Clone)]
-struct Reader {
-    tokens: Vec<String>,
+// Location of a token in the source: byte offsets plus the (line, column)
+// of its first character, both counted from 1.
+#[derive(Debug, Clone, Copy)]
+struct Span {
+    start: usize,
+    end: usize,
+    line: usize,
+    col: usize,
+}
+
+// Splits the source into token spans on demand, skipping whitespace, commas
+// and comments. Accepts the same grammar as the old regex tokenizer.
+struct Lexer<'a> {
+    src: &'a str,
     pos: usize,
+    line: usize,
+    col: usize,
 }
 
-impl Reader {
-    fn next(&mut self) -> Result<String, MalErr> {
-        self.pos = self.pos + 1;
-        Ok(self
-            .tokens
-            .get(self.pos - 1)
-            .ok_or(ErrString("underflow".to_string()))?
-            .to_string())
+// Characters that end a symbol, number or keyword
+fn is_delim(c: char) -> bool {
+    c.is_whitespace() || "[]{}('\"`,;)".contains(c)
+}
+
+impl<'a> Lexer<'a> {
+    fn new(src: &'a str) -> Lexer<'a> {
+        Lexer {
+            src,
+            pos: 0,
+            line: 1,
+            col: 1,
+        }
     }
-    fn peek(&self) -> Result<String, MalErr> {
-        Ok(self
-            .tokens
-            .get(self.pos)
-            .ok_or(ErrString("underflow".to_string()))?
-            .to_string())
+    fn peek_char(&self) -> Option<char> {
+        self.src[self.pos..].chars().next()
+    }
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek_char()?;
+        self.pos += c.len_utf8();
+        if c == '\n' {
+            self.line += 1;
+            self.col = 1;
+        } else {
+            self.col += 1;
+        }
+        Some(c)
+    }
+    fn bump_while<F: Fn(char) -> bool>(&mut self, f: F) {
+        while self.peek_char().is_some_and(&f) {
+            self.bump();
+        }
     }
 }
 
-fn tokenize(str: &str) -> Vec<String> {
-    lazy_static! {
-        static ref RE: Regex = Regex::new(
-            r###"[\s,]*(~@|[\[\]{}()'`~^@]|"(?:\\.|[^\\"])*"?|;.*|[^\s\[\]{}('"`,;)]+)"###
-        )
-        .unwrap();
+impl<'a> Iterator for Lexer<'a> {
+    type Item = Span;
+
+    fn next(&mut self) -> Option<Span> {
+        loop {
+            self.bump_while(|c| c.is_whitespace() || c == ',');
+            if self.peek_char() != Some(';') {
+                break;
+            }
+            self.bump_while(|c| c != '\n');
+        }
+        let (start, line, col) = (self.pos, self.line, self.col);
+        match self.bump()? {
+            '~' => {
+                if self.peek_char() == Some('@') {
+                    self.bump();
+                }
+            }
+            '#' if self.peek_char() == Some('{') => {
+                self.bump();
+            }
+            '[' | ']' | '{' | '}' | '(' | ')' | '\'' | '`' | '^' | '@' => {}
+            '"' => loop {
+                match self.bump() {
+                    Some('"') | None => break,
+                    Some('\\') => {
+                        self.bump();
+                    }
+                    Some(_) => {}
+                }
+            },
+            '\\' => {
+                if self.peek_char().is_some_and(|c| !c.is_whitespace()) {
+                    self.bump();
+                }
+                self.bump_while(|c| !is_delim(c));
+            }
+            _ => self.bump_while(|c| !is_delim(c)),
+        }
+        Some(Span {
+            start,
+            end: self.pos,
+            line,
+            col,
+        })
     }
+}
 
-    let mut res = vec![];
-    for cap in RE.captures_iter(str) {
-        if cap[1].starts_with(";") {
+struct Reader<'a> {
+    src: &'a str,
+    lexer: Lexer<'a>,
+    peeked: Option<Span>,
+    file: Option<Rc<String>>,
+    // set when reading failed because the input ran out
+    eof: bool,
+}
+
+impl<'a> Reader<'a> {
+    fn next(&mut self) -> Result<&'a str, MalErr> {
+        let span = match self.peeked.take() {
+            Some(span) => span,
+            None => match self.lexer.next() {
+                Some(span) => span,
+                None => return Err(self.underflow()),
+            },
+        };
+        Ok(&self.src[span.start..span.end])
+    }
+    fn peek_span(&mut self) -> Option<Span> {
+        if self.peeked.is_none() {
+            self.peeked = self.lexer.next();
+        }
+        self.peeked
+    }
+    fn peek(&mut self) -> Result<&'a str, MalErr> {
+        match self.peek_span() {
+            Some(span) => Ok(&self.src[span.start..span.end]),
+            None => Err(self.underflow()),
+        }
+    }
+    fn underflow(&mut self) -> MalErr {
+        self.eof = true;
+        ErrString("underflow".to_string())
+    }
+    // source position of the next token
+    fn loc(&mut self) -> SrcPos {
+        let (line, col) = self.peek_span().map_or((0, 0), |s| (s.line, s.col));
+        SrcPos {
+            file: self.file.clone(),
+            line,
+            col,
+        }
+    }
+}
+
+// Supports \n \t \r \0 \" \\ and unicode escapes written either as
+// \uXXXX or \u{X...}.
+fn unescape_str(s: &str) -> Result<String, MalErr> {
+    let mut res = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            res.push(c);
             continue;
         }
-        res.push(String::from(&cap[1]));
+        match chars.next() {
+            Some('n') => res.push('\n'),
+            Some('t') => res.push('\t'),
+            Some('r') => res.push('\r'),
+            Some('0') => res.push('\0'),
+            Some('"') => res.push('"'),
+            Some('\\') => res.push('\\'),
+            Some('u') => {
+                // \uXXXX takes exactly four digits, \u{X...} up to six
+                let rest = chars.as_str();
+                let (hex, len, valid_len) = match rest.strip_prefix('{') {
+                    Some(braced) => match braced.find('}') {
+                        Some(end) => (&braced[..end], end + 2, end <= 6),
+                        None => return Err(ErrString("unterminated unicode escape".to_string())),
+                    },
+                    None => {
+                        let end = rest.char_indices().nth(4).map_or(rest.len(), |(i, _)| i);
+                        let hex = &rest[..end];
+                        (hex, 4, hex.len() == 4)
+                    }
+                };
+                match unicode_char(hex).filter(|_| valid_len) {
+                    Some(c) => res.push(c),
+                    None => return Err(ErrString(format!("invalid unicode escape '{}'", hex))),
+                }
+                chars = rest[len..].chars();
+            }
+            Some(c) => return Err(ErrString(format!("unsupported escape '\\{}'", c))),
+            None => return Err(ErrString("unterminated escape".to_string())),
+        }
     }
-    res
+    Ok(res)
 }
 
-fn unescape_str(s: &str) -> String {
-    lazy_static! {
-        static ref RE: Regex = Regex::new(r#"\\(.)"#).unwrap();
+// The character with the code point written in `hex`, which must be ASCII
+// hex digits only
+fn unicode_char(hex: &str) -> Option<char> {
+    if hex.is_empty() || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    u32::from_str_radix(hex, 16).ok().and_then(std::char::from_u32)
+}
+
+// A character literal without the leading backslash: a single character,
+// one of the names below or a unicode escape like u00e9.
+fn read_char(name: &str) -> MalRet {
+    let mut chars = name.chars();
+    if let (Some(c), None) = (chars.next(), chars.next()) {
+        return Ok(Char(c));
+    }
+    match name {
+        "newline" => Ok(Char('\n')),
+        "space" => Ok(Char(' ')),
+        "tab" => Ok(Char('\t')),
+        "return" => Ok(Char('\r')),
+        "backspace" => Ok(Char('\u{8}')),
+        "formfeed" => Ok(Char('\u{c}')),
+        _ if name.starts_with('u') && name.len() == 5 => {
+            match unicode_char(&name[1..]) {
+                Some(c) => Ok(Char(c)),
+                None => error(&format!("invalid character '\\{}'", name)),
+            }
+        }
+        _ => error(&format!("invalid character '\\{}'", name)),
     }
-    RE.replace_all(&s, |caps: &Captures| {
-        format!("{}", if &caps[1] == "n" { "\n" } else { &caps[1] })
-    })
-    .to_string()
 }
 
 fn read_atom(rdr: &mut Reader) -> MalRet {
     lazy_static! {
         static ref INT_RE: Regex = Regex::new(r"^-?[0-9]+$").unwrap();
+        static ref FLOAT_RE: Regex =
+            Regex::new(r"^-?[0-9]+(\.[0-9]*)?([eE][-+]?[0-9]+)?$").unwrap();
         static ref STR_RE: Regex = Regex::new(r#""(?:\\.|[^\\"])*""#).unwrap();
     }
     let token = rdr.next()?;
-    match &token[..] {
+    match token {
         "nil" => Ok(Nil),
         "false" => Ok(Bool(false)),
         "true" => Ok(Bool(true)),
+        "##Inf" => Ok(Float(f64::INFINITY)),
+        "##-Inf" => Ok(Float(f64::NEG_INFINITY)),
+        "##NaN" => Ok(Float(f64::NAN)),
         _ => {
-            if INT_RE.is_match(&token) {
-                Ok(Int(token.parse().unwrap()))
-            } else if STR_RE.is_match(&token) {
-                Ok(Str(unescape_str(&token[1..token.len() - 1])))
-            } else if token.starts_with("\"") {
+            if INT_RE.is_match(token) {
+                match token.parse() {
+                    Ok(i) => Ok(Int(i)),
+                    // too large for an i64
+                    Err(_) => match token.parse() {
+                        Ok(b) => Ok(big_int(b)),
+                        Err(_) => error(&format!("invalid number '{}'", token)),
+                    },
+                }
+            } else if FLOAT_RE.is_match(token) {
+                match token.parse() {
+                    Ok(f) => Ok(Float(f)),
+                    Err(_) => error(&format!("invalid number '{}'", token)),
+                }
+            } else if STR_RE.is_match(token) {
+                Ok(Str(unescape_str(&token[1..token.len() - 1])?))
+            } else if token.starts_with('"') {
+                rdr.eof = true;
                 error("expected '\"', got EOF")
-            } else if token.starts_with(":") {
-                Ok(Str(format!("\u{29e}{}", &token[1..])))
+            } else if let Some(name) = token.strip_prefix(':') {
+                Ok(keyword(name))
+            } else if let Some(name) = token.strip_prefix('\\') {
+                read_char(name)
             } else {
                 Ok(Sym(token.to_string()))
             }
@@ -83,31 +279,36 @@ fn read_atom(rdr: &mut Reader) -> MalRet {
     }
 }
 
-fn read_seq(rdr: &mut Reader, end: &str) -> MalRet {
+// Reads the forms up to `end`, after the opening token, along with the
+// positions of those that are symbols.
+fn read_seq(rdr: &mut Reader, end: &str) -> Result<(Vec<MalVal>, Vec<Option<SrcPos>>), MalErr> {
     let mut seq: Vec<MalVal> = vec![];
+    let mut sym_pos = vec![];
     rdr.next()?;
     loop {
         let token = match rdr.peek() {
             Ok(t) => t,
-            Err(_) => return error(&format!("expected '{}', got EOF", end)),
+            Err(_) => {
+                rdr.eof = true;
+                return Err(ErrString(format!("expected '{}', got EOF", end)));
+            }
         };
         if token == end {
             break;
         }
-        seq.push(read_form(rdr)?)
+        let pos = rdr.loc();
+        let form = read_form(rdr)?;
+        sym_pos.push(if let Sym(_) = form { Some(pos) } else { None });
+        seq.push(form);
     }
     let _ = rdr.next();
-    match end {
-        ")" => Ok(list!(seq)),
-        "]" => Ok(vector!(seq)),
-        "}" => hash_map(seq),
-        _ => error("read_seq unknown end value"),
-    }
+    Ok((seq, sym_pos))
 }
 
 fn read_form(rdr: &mut Reader) -> MalRet {
     let token = rdr.peek()?;
-    match &token[..] {
+    let pos = rdr.loc();
+    match token {
         "'" => {
             let _ = rdr.next();
             Ok(list![Sym("quote".to_string()), read_form(rdr)?])
@@ -134,23 +335,121 @@ fn read_form(rdr: &mut Reader) -> MalRet {
             Ok(list![Sym("deref".to_string()), read_form(rdr)?])
         }
         ")" => error("unexpected ')'"),
-        "(" => read_seq(rdr, ")"),
+        "(" => {
+            let (seq, sym_pos) = read_seq(rdr, ")")?;
+            Ok(list!(seq.into()).with_sym_pos(sym_pos))
+        }
         "]" => error("unexpected ']'"),
-        "[" => read_seq(rdr, "]"),
+        "[" => {
+            let (seq, sym_pos) = read_seq(rdr, "]")?;
+            Ok(vector!(seq.into()).with_sym_pos(sym_pos))
+        }
         "}" => error("unexpected '}'"),
-        "{" => read_seq(rdr, "}"),
+        "{" => hash_map(&read_seq(rdr, "}")?.0),
+        "#{" => hash_set(&read_seq(rdr, "}")?.0),
         _ => read_atom(rdr),
     }
+    .map(|form| form.with_pos(pos))
+}
+
+fn new_reader<'a>(str: &'a str, file: Option<&str>) -> Reader<'a> {
+    Reader {
+        src: str,
+        lexer: Lexer::new(str),
+        peeked: None,
+        file: file.map(|f| Rc::new(f.to_string())),
+        eof: false,
+    }
+}
+
+// Errors from reading are :reader error maps, with the position of the
+// form being read when it's known
+fn reader_error(e: MalErr, pos: Option<SrcPos>) -> MalErr {
+    match (e, pos) {
+        (ErrString(msg), Some(pos)) => {
+            let context = [("line", Int(pos.line as i64)), ("col", Int(pos.col as i64))];
+            err_info("reader", &msg, &context).at(Some(pos))
+        }
+        (ErrString(msg), None) => err_info("reader", &msg, &[]),
+        (e, _) => e,
+    }
 }
 
 pub fn read_str(str: String) -> MalRet {
-    let tokens = tokenize(&str);
-    //println!("tokens: {:?}", tokens);
-    if tokens.len() == 0 {
-        return error("no input");
-    }
-    read_form(&mut Reader {
-        pos: 0,
-        tokens: tokens,
-    })
+    let mut rdr = new_reader(&str, None);
+    if rdr.peek_span().is_none() {
+        return Err(err_info("reader", "no input", &[]));
+    }
+    read_form(&mut rdr).map_err(|e| reader_error(e, None))
+}
+
+// Read every top-level form in `str` into a list, which records the
+// positions of the forms that are symbols. Errors carry the position of
+// the form being read, which is only shown when `file` is given.
+pub fn read_all(str: String, file: Option<&str>) -> MalRet {
+    let mut rdr = new_reader(&str, file);
+    let mut forms = vec![];
+    let mut sym_pos = vec![];
+    while rdr.peek_span().is_some() {
+        let pos = rdr.loc();
+        let form = read_form(&mut rdr).map_err(|e| reader_error(e, Some(pos.clone())))?;
+        sym_pos.push(if let Sym(_) = form { Some(pos) } else { None });
+        forms.push(form);
+    }
+    Ok(list!(forms.into()).with_sym_pos(sym_pos))
+}
+
+// Drop a leading `#!` line so that scripts can be run directly. The newline
+// is kept so that line numbers are unchanged.
+pub fn skip_shebang(str: &str) -> &str {
+    if str.starts_with("#!") {
+        &str[str.find('\n').unwrap_or(str.len())..]
+    } else {
+        str
+    }
+}
+
+// True when `str` stops part way through a form, with unbalanced brackets
+// or an unterminated string, so that more input could still complete it.
+pub fn is_incomplete(str: &str) -> bool {
+    let mut rdr = new_reader(str, None);
+    while rdr.peek_span().is_some() {
+        if read_form(&mut rdr).is_err() {
+            return rdr.eof;
+        }
+    }
+    false
+}
+
+// Rewrite a form spread over several lines as a single line with the same
+// meaning. Line breaks and comments between tokens become a space and
+// newlines inside strings are escaped.
+pub fn one_line(str: &str) -> String {
+    let mut res = String::with_capacity(str.len());
+    let mut last = 0;
+    for span in Lexer::new(str) {
+        let gap = &str[last..span.start];
+        if gap.contains('\n') {
+            res.push(' ');
+        } else {
+            res.push_str(gap);
+        }
+        let token = &str[span.start..span.end];
+        if token.starts_with('"') {
+            res.push_str(&token.replace('\n', "\\n").replace('\r', "\\r"));
+        } else {
+            res.push_str(token);
+        }
+        last = span.end;
+    }
+    let gap = &str[last..];
+    if !gap.contains('\n') {
+        res.push_str(gap);
+    }
+    res.trim().to_string()
+}
+
+// Byte ranges of the tokens in `str`, comments excluded, for the line editor
+pub fn token_spans(str: &str) -> Vec<(usize, usize)> {
+    Lexer::new(str).map(|span| (span.start, span.end)).collect()
 }
@@ -0,0 +1,396 @@
+// Line editing support for the stepA REPL: tab completion of symbols and
+// of file names in load-file, bracket matching, syntax highlighting and
+// the locations of the history and init files.
+
+use std::borrow::Cow::{self, Borrowed, Owned};
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use rustyline::completion::{Completer, FilenameCompleter, Pair};
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::{Context, Editor, Helper};
+
+use crate::env::{env_keys, Env};
+use crate::reader::token_spans;
+use crate::types::MalVal::{self, List, Vector};
+
+// The special forms with their usage, for completion and :doc
+pub const SPECIAL_FORMS: &[(&str, &str)] = &[
+    ("catch*", "(catch* [selector] sym handler)"),
+    ("def!", "(def! sym expr)"),
+    ("defmacro!", "(defmacro! sym fn)"),
+    ("do", "(do expr...)"),
+    ("eval", "(eval form)"),
+    ("finally*", "(finally* expr...)"),
+    ("fn*", "(fn* [params...] body)"),
+    ("if", "(if test then [else])"),
+    ("let*", "(let* [sym expr...] body)"),
+    ("loop*", "(loop* [sym expr...] body)"),
+    ("macroexpand", "(macroexpand form)"),
+    ("quasiquote", "(quasiquote form)"),
+    ("quasiquoteexpand", "(quasiquoteexpand form)"),
+    ("quote", "(quote form)"),
+    ("recur", "(recur expr...)"),
+    ("try*", "(try* expr (catch* [selector] sym handler)... [(finally* expr...)])"),
+];
+
+// Line width used by pretty
+const WIDTH: usize = 80;
+
+const HISTORY_SIZE: usize = 1000;
+
+// Where the REPL history is kept: $MAL_HISTORY, a .mal-history file in
+// `cwd` if there is one, or $XDG_STATE_HOME/mal/history. None when
+// $MAL_HISTORY is empty. `var` looks up environment variables.
+pub fn history_path<V>(var: V, cwd: &Path) -> Option<PathBuf>
+where
+    V: Fn(&str) -> Option<OsString>,
+{
+    if let Some(path) = var("MAL_HISTORY") {
+        return if path.is_empty() {
+            None
+        } else {
+            Some(PathBuf::from(path))
+        };
+    }
+    let local = cwd.join(".mal-history");
+    if local.exists() {
+        return Some(local);
+    }
+    let state = match var("XDG_STATE_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(var("HOME")?).join(".local").join("state"),
+    };
+    Some(state.join("mal").join("history"))
+}
+
+// The number of history entries kept, from $MAL_HISTORY_SIZE
+pub fn history_size<V>(var: V) -> usize
+where
+    V: Fn(&str) -> Option<OsString>,
+{
+    var("MAL_HISTORY_SIZE")
+        .and_then(|s| s.to_str()?.parse().ok())
+        .unwrap_or(HISTORY_SIZE)
+}
+
+pub fn save_history<H: Helper>(rl: &Editor<H>, path: &Path) -> rustyline::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    rl.save_history(path)
+}
+
+// ~/.malrc, if it exists, is loaded before an interactive session
+pub fn init_file<V>(var: V) -> Option<PathBuf>
+where
+    V: Fn(&str) -> Option<OsString>,
+{
+    let path = PathBuf::from(var("HOME")?).join(".malrc");
+    if path.exists() {
+        Some(path)
+    } else {
+        None
+    }
+}
+
+const STRING_COLOR: &str = "\x1b[32m";
+const KEYWORD_COLOR: &str = "\x1b[35m";
+const NUMBER_COLOR: &str = "\x1b[36m";
+const BRACKET_COLOR: &str = "\x1b[1;34m";
+const RESET: &str = "\x1b[0m";
+
+pub struct MalHelper {
+    pub env: Env,
+    files: FilenameCompleter,
+}
+
+impl MalHelper {
+    pub fn new(env: Env) -> MalHelper {
+        MalHelper {
+            env,
+            files: FilenameCompleter::new(),
+        }
+    }
+}
+
+fn is_open(token: &str) -> bool {
+    matches!(token, "(" | "[" | "{" | "#{")
+}
+
+fn is_close(token: &str) -> bool {
+    matches!(token, ")" | "]" | "}")
+}
+
+fn is_number(token: &str) -> bool {
+    let digits = token.strip_prefix('-').unwrap_or(token);
+    digits.starts_with(|c: char| c.is_ascii_digit()) || token.starts_with("##")
+}
+
+// Index into `spans` of the bracket matching the one under or just before
+// the cursor
+fn matching_bracket(line: &str, spans: &[(usize, usize)], pos: usize) -> Option<usize> {
+    let text = |i: usize| &line[spans[i].0..spans[i].1];
+    let is_bracket = |i: &usize| is_open(text(*i)) || is_close(text(*i));
+    let at = spans
+        .iter()
+        .position(|&(start, _)| start == pos)
+        .filter(is_bracket)
+        .or_else(|| spans.iter().position(|&(_, end)| end == pos).filter(is_bracket))?;
+    let mut depth = 0;
+    if is_open(text(at)) {
+        for i in at..spans.len() {
+            if is_open(text(i)) {
+                depth += 1;
+            } else if is_close(text(i)) {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i);
+                }
+            }
+        }
+    } else if is_close(text(at)) {
+        for i in (0..=at).rev() {
+            if is_close(text(i)) {
+                depth += 1;
+            } else if is_open(text(i)) {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i);
+                }
+            }
+        }
+    }
+    None
+}
+
+// Print `ast` readably, spreading lists and vectors that don't fit in the
+// rest of the line over several lines
+pub fn pretty(ast: &MalVal) -> String {
+    let mut out = String::new();
+    pretty_into(ast, 0, &mut out);
+    out
+}
+
+fn pretty_into(ast: &MalVal, indent: usize, out: &mut String) {
+    let flat = ast.pr_str(true);
+    // lists indent their arguments past the head, vectors line up
+    let (seq, open, close, step) = match ast {
+        List(l, _) if indent + flat.chars().count() > WIDTH => (l, "(", ")", 2),
+        Vector(v, _) if indent + flat.chars().count() > WIDTH => (v, "[", "]", 1),
+        _ => {
+            out.push_str(&flat);
+            return;
+        }
+    };
+    out.push_str(open);
+    for (i, v) in seq.iter().enumerate() {
+        if i > 0 {
+            out.push('\n');
+            out.push_str(&" ".repeat(indent + step));
+        }
+        pretty_into(v, indent + if i == 0 { 1 } else { step }, out);
+    }
+    out.push_str(close);
+}
+
+impl Completer for MalHelper {
+    type Candidate = Pair;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let spans = token_spans(&line[..pos]);
+        let start = match spans.last() {
+            Some(&(start, end)) if end == pos => start,
+            _ => pos,
+        };
+        let word = &line[start..pos];
+        if word.starts_with('"') {
+            // only strings passed to load-file are completed, as paths
+            let n = spans.len();
+            if n > 1 && &line[spans[n - 2].0..spans[n - 2].1] == "load-file" {
+                return self.files.complete(line, pos, ctx);
+            }
+            return Ok((start, vec![]));
+        }
+        let names = env_keys(&self.env);
+        let mut candidates: Vec<&str> = SPECIAL_FORMS
+            .iter()
+            .map(|&(name, _)| name)
+            .chain(names.iter().map(|s| &s[..]))
+            .filter(|s| s.starts_with(word))
+            .collect();
+        candidates.sort();
+        candidates.dedup();
+        let pairs = candidates
+            .into_iter()
+            .map(|s| Pair {
+                display: s.to_string(),
+                replacement: s.to_string(),
+            })
+            .collect();
+        Ok((start, pairs))
+    }
+}
+
+impl Hinter for MalHelper {}
+
+impl Highlighter for MalHelper {
+    fn highlight<'l>(&self, line: &'l str, pos: usize) -> Cow<'l, str> {
+        let spans = token_spans(line);
+        if spans.is_empty() {
+            return Borrowed(line);
+        }
+        let bracket = matching_bracket(line, &spans, pos);
+        let mut res = String::with_capacity(line.len() * 2);
+        let mut last = 0;
+        for (i, &(start, end)) in spans.iter().enumerate() {
+            let token = &line[start..end];
+            let color = if Some(i) == bracket {
+                BRACKET_COLOR
+            } else if token.starts_with('"') {
+                STRING_COLOR
+            } else if token.starts_with(':') {
+                KEYWORD_COLOR
+            } else if is_number(token) {
+                NUMBER_COLOR
+            } else {
+                ""
+            };
+            res.push_str(&line[last..start]);
+            if color.is_empty() {
+                res.push_str(token);
+            } else {
+                res.push_str(color);
+                res.push_str(token);
+                res.push_str(RESET);
+            }
+            last = end;
+        }
+        res.push_str(&line[last..]);
+        Owned(res)
+    }
+
+    // Every keystroke can change the highlighting, e.g. by opening a string
+    fn highlight_char(&self, _line: &str, _pos: usize) -> bool {
+        true
+    }
+}
+
+impl Helper for MalHelper {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::env::{env_new, env_sets};
+    use crate::reader::read_str;
+    use crate::types::MalVal::Int;
+    use rustyline::history::History;
+    use std::collections::HashMap;
+
+    // The start of the bracket matching the one at `pos`
+    fn matching(line: &str, pos: usize) -> Option<usize> {
+        let spans = token_spans(line);
+        matching_bracket(line, &spans, pos).map(|i| spans[i].0)
+    }
+
+    fn completions(helper: &MalHelper, line: &str) -> (usize, Vec<String>) {
+        let history = History::new();
+        let (start, pairs) = helper.complete(line, line.len(), &Context::new(&history)).unwrap();
+        (start, pairs.into_iter().map(|p| p.replacement).collect())
+    }
+
+    #[test]
+    fn matches_brackets() {
+        assert_eq!(matching("(a [b c])", 0), Some(8));
+        assert_eq!(matching("(a [b c])", 3), Some(7));
+        // just after a closing bracket
+        assert_eq!(matching("(a [b c])", 9), Some(0));
+        assert_eq!(matching("(a [b c])", 2), None);
+        assert_eq!(matching("(a [b c)", 0), None);
+    }
+
+    #[test]
+    fn ignores_brackets_in_strings_and_comments() {
+        assert_eq!(matching("(a \")]\" b)", 0), Some(9));
+        assert_eq!(matching("(a ; ) ]\n b)", 0), Some(11));
+        assert_eq!(matching("(a ; (\n b)", 10), Some(0));
+    }
+
+    #[test]
+    fn completes_special_forms_and_env_symbols() {
+        let env = env_new(None);
+        env_sets(&env, "let-me", Int(1));
+        env_sets(&env, "other", Int(2));
+        let helper = MalHelper::new(env);
+        assert_eq!(completions(&helper, "(le"), (1, vec!["let*".into(), "let-me".into()]));
+        assert_eq!(completions(&helper, "(+ 1 ot"), (5, vec!["other".into()]));
+        assert_eq!(completions(&helper, "(try"), (1, vec!["try*".into()]));
+        // strings are only completed in load-file, as paths
+        assert_eq!(completions(&helper, "(str \"le"), (5, vec![]));
+    }
+
+    #[test]
+    fn completes_from_outer_envs() {
+        let outer = env_new(None);
+        env_sets(&outer, "outer-sym", Int(1));
+        let helper = MalHelper::new(env_new(Some(outer)));
+        assert_eq!(completions(&helper, "outer"), (0, vec!["outer-sym".into()]));
+    }
+
+    #[test]
+    fn history_and_init_file_locations() {
+        let dir = std::env::temp_dir().join(format!("mal-repl-test-{}", std::process::id()));
+        let home = dir.join("home");
+        fs::create_dir_all(&home).unwrap();
+        let vars = |pairs: &[(&str, &str)]| {
+            let mut vars: HashMap<String, OsString> =
+                pairs.iter().map(|&(k, v)| (k.to_string(), v.into())).collect();
+            vars.insert("HOME".to_string(), home.clone().into());
+            move |name: &str| vars.get(name).cloned()
+        };
+
+        // $MAL_HISTORY comes first, and turns history off when empty
+        let var = vars(&[("MAL_HISTORY", "/tmp/mal-hist")]);
+        assert_eq!(history_path(var, &dir), Some(PathBuf::from("/tmp/mal-hist")));
+        assert_eq!(history_path(vars(&[("MAL_HISTORY", "")]), &dir), None);
+
+        // then $XDG_STATE_HOME, defaulting to ~/.local/state
+        let state = home.join(".local/state/mal/history");
+        assert_eq!(history_path(vars(&[]), &dir), Some(state));
+        let xdg = vars(&[("XDG_STATE_HOME", "/state")]);
+        assert_eq!(history_path(&xdg, &dir), Some(PathBuf::from("/state/mal/history")));
+
+        // unless there's a .mal-history in the current directory
+        fs::write(dir.join(".mal-history"), "").unwrap();
+        assert_eq!(history_path(&xdg, &dir), Some(dir.join(".mal-history")));
+
+        assert_eq!(history_size(vars(&[])), HISTORY_SIZE);
+        assert_eq!(history_size(vars(&[("MAL_HISTORY_SIZE", "50")])), 50);
+        assert_eq!(history_size(vars(&[("MAL_HISTORY_SIZE", "lots")])), HISTORY_SIZE);
+
+        assert_eq!(init_file(vars(&[])), None);
+        fs::write(home.join(".malrc"), "").unwrap();
+        assert_eq!(init_file(vars(&[])), Some(home.join(".malrc")));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn pretty_prints_within_the_width() {
+        let short = read_str("(a [b c] {:d 1})".to_string()).unwrap();
+        assert_eq!(pretty(&short), "(a [b c] {:d 1})");
+
+        let words = vec!["word"; 20].join(" ");
+        let long = read_str(format!("(f [{}] x)", words)).unwrap();
+        let expected = format!("(f\n  [{}]\n  x)", vec!["word"; 20].join("\n   "));
+        assert_eq!(pretty(&long), expected);
+        assert!(pretty(&long).lines().all(|l| l.len() <= WIDTH));
+    }
+}
@@ -2,6 +2,8 @@
 extern crate lazy_static;
 extern crate fnv;
 extern crate itertools;
+extern crate num_bigint;
+extern crate num_traits;
 extern crate regex;
 
 extern crate rustyline;
@@ -13,6 +15,7 @@ use rustyline::Editor;
 mod types;
 use crate::types::format_error;
 mod printer;
+#[allow(dead_code)]
 mod reader;
 // TODO: figure out a way to avoid including env
 #[allow(dead_code)]
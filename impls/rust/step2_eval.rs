@@ -6,6 +6,8 @@ use fnv::FnvHashMap;
 extern crate lazy_static;
 extern crate fnv;
 extern crate itertools;
+extern crate num_bigint;
+extern crate num_traits;
 extern crate regex;
 
 extern crate rustyline;
@@ -16,9 +18,10 @@ use rustyline::Editor;
 #[allow(dead_code)]
 mod types;
 use crate::types::MalErr::ErrString;
-use crate::types::MalVal::{Hash, Int, List, Nil, Sym, Vector};
-use crate::types::{error, format_error, func, MalArgs, MalErr, MalRet, MalVal};
+use crate::types::MalVal::{Hash, Int, List, Set, Sym, Vector};
+use crate::types::{error, format_error, func, hash_set, MalArgs, MalErr, MalMap, MalRet, MalVal};
 mod printer;
+#[allow(dead_code)]
 mod reader;
 // TODO: figure out a way to avoid including env
 #[allow(dead_code)]
@@ -43,21 +46,28 @@ fn eval_ast(ast: &MalVal, env: &Env) -> MalRet {
             for a in v.iter() {
                 lst.push(eval(a.clone(), env.clone())?)
             }
-            Ok(list!(lst))
+            Ok(list!(lst.into()))
         }
         Vector(v, _) => {
             let mut lst: MalArgs = vec![];
             for a in v.iter() {
                 lst.push(eval(a.clone(), env.clone())?)
             }
-            Ok(vector!(lst))
+            Ok(vector!(lst.into()))
         }
         Hash(hm, _) => {
-            let mut new_hm: FnvHashMap<String, MalVal> = FnvHashMap::default();
+            let mut new_hm = MalMap::default();
             for (k, v) in hm.iter() {
-                new_hm.insert(k.to_string(), eval(v.clone(), env.clone())?);
+                new_hm.insert(k.clone(), eval(v.clone(), env.clone())?);
+            }
+            Ok(Hash(new_hm, Rc::default()))
+        }
+        Set(hs, _) => {
+            let mut lst: MalArgs = vec![];
+            for a in hs.values() {
+                lst.push(eval(a.clone(), env.clone())?)
             }
-            Ok(Hash(Rc::new(new_hm), Rc::new(Nil)))
+            hash_set(&lst)
         }
         _ => Ok(ast.clone()),
     }
@@ -72,7 +82,7 @@ fn eval(ast: MalVal, env: Env) -> MalRet {
             match eval_ast(&ast, &env)? {
                 List(ref el, _) => {
                     let ref f = el[0].clone();
-                    f.apply(el[1..].to_vec())
+                    f.apply(el.iter().skip(1).cloned().collect())
                 }
                 _ => error("expected a list"),
             }
@@ -1,12 +1,13 @@
 use std::rc::Rc;
 //use std::collections::HashMap;
-use fnv::FnvHashMap;
 use itertools::Itertools;
 
 #[macro_use]
 extern crate lazy_static;
 extern crate fnv;
 extern crate itertools;
+extern crate num_bigint;
+extern crate num_traits;
 extern crate regex;
 
 extern crate rustyline;
@@ -15,10 +16,12 @@ use rustyline::Editor;
 
 #[macro_use]
 mod types;
-use crate::types::MalVal::{Bool, Hash, List, MalFunc, Nil, Sym, Vector};
-use crate::types::{error, format_error, MalArgs, MalErr, MalRet, MalVal};
+use crate::types::MalVal::{Bool, Hash, List, MalFunc, Nil, Set, Sym, Vector};
+use crate::types::{error, format_error, hash_set, MalArgs, MalErr, MalMap, MalRet, MalVal};
+#[allow(dead_code)]
 mod env;
 mod printer;
+#[allow(dead_code)]
 mod reader;
 use crate::env::{env_get, env_new, env_set, env_sets, Env};
 #[macro_use]
@@ -38,21 +41,28 @@ fn eval_ast(ast: &MalVal, env: &Env) -> MalRet {
             for a in v.iter() {
                 lst.push(eval(a.clone(), env.clone())?)
             }
-            Ok(list!(lst))
+            Ok(list!(lst.into()))
         }
         Vector(v, _) => {
             let mut lst: MalArgs = vec![];
             for a in v.iter() {
                 lst.push(eval(a.clone(), env.clone())?)
             }
-            Ok(vector!(lst))
+            Ok(vector!(lst.into()))
         }
         Hash(hm, _) => {
-            let mut new_hm: FnvHashMap<String, MalVal> = FnvHashMap::default();
+            let mut new_hm = MalMap::default();
             for (k, v) in hm.iter() {
-                new_hm.insert(k.to_string(), eval(v.clone(), env.clone())?);
+                new_hm.insert(k.clone(), eval(v.clone(), env.clone())?);
             }
-            Ok(Hash(Rc::new(new_hm), Rc::new(Nil)))
+            Ok(Hash(new_hm, Rc::default()))
+        }
+        Set(hs, _) => {
+            let mut lst: MalArgs = vec![];
+            for a in hs.values() {
+                lst.push(eval(a.clone(), env.clone())?)
+            }
+            hash_set(&lst)
         }
         _ => Ok(ast.clone()),
     }
@@ -95,7 +105,7 @@ fn eval(ast: MalVal, env: Env) -> MalRet {
                     };
                     eval(a2, let_env)
                 }
-                Sym(ref a0sym) if a0sym == "do" => match eval_ast(&list!(l[1..].to_vec()), &env)? {
+                Sym(ref a0sym) if a0sym == "do" => match eval_ast(&list!(l.skip(1)), &env)? {
                     List(el, _) => Ok(el.last().unwrap_or(&Nil).clone()),
                     _ => error("invalid do form"),
                 },
@@ -112,9 +122,9 @@ fn eval(ast: MalVal, env: Env) -> MalRet {
                     let (a1, a2) = (l[1].clone(), l[2].clone());
                     Ok(MalFunc {
                         eval: eval,
-                        ast: Rc::new(a2),
+                        name: None,
+                        arities: Rc::new(vec![(a1, a2)]),
                         env: env,
-                        params: Rc::new(a1),
                         is_macro: false,
                         meta: Rc::new(Nil),
                     })
@@ -122,7 +132,7 @@ fn eval(ast: MalVal, env: Env) -> MalRet {
                 _ => match eval_ast(&ast, &env)? {
                     List(ref el, _) => {
                         let ref f = el[0].clone();
-                        f.apply(el[1..].to_vec())
+                        f.apply(el.iter().skip(1).cloned().collect())
                     }
                     _ => error("expected a list"),
                 },
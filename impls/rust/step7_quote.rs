@@ -1,12 +1,13 @@
 use std::rc::Rc;
 //use std::collections::HashMap;
-use fnv::FnvHashMap;
 use itertools::Itertools;
 
 #[macro_use]
 extern crate lazy_static;
 extern crate fnv;
 extern crate itertools;
+extern crate num_bigint;
+extern crate num_traits;
 extern crate regex;
 
 extern crate rustyline;
@@ -15,12 +16,14 @@ use rustyline::Editor;
 
 #[macro_use]
 mod types;
-use crate::types::MalVal::{Bool, Func, Hash, List, MalFunc, Nil, Str, Sym, Vector};
-use crate::types::{error, format_error, MalArgs, MalErr, MalRet, MalVal};
+use crate::types::MalVal::{Bool, Func, Hash, List, MalFunc, Nil, Set, Str, Sym, Vector};
+use crate::types::{error, format_error, hash_set, MalArgs, MalErr, MalMap, MalRet, MalSeq, MalVal};
+#[allow(dead_code)]
 mod env;
 mod printer;
+#[allow(dead_code)]
 mod reader;
-use crate::env::{env_bind, env_get, env_new, env_set, env_sets, Env};
+use crate::env::{env_get, env_new, env_set, env_sets, Env};
 #[macro_use]
 mod core;
 
@@ -31,7 +34,7 @@ fn read(str: &str) -> MalRet {
 
 // eval
 
-fn qq_iter(elts: &MalArgs) -> MalVal {
+fn qq_iter(elts: &MalSeq) -> MalVal {
     let mut acc = list![];
     for elt in elts.iter().rev() {
         if let List(v, _) = elt {
@@ -59,10 +62,10 @@ fn quasiquote(ast: &MalVal) -> MalVal {
                     }
                 }
             }
-            return qq_iter(&v);
+            return qq_iter(v);
         },
-        Vector(v, _) => return list![Sym("vec".to_string()), qq_iter(&v)],
-        Hash(_, _) | Sym(_)=> return list![Sym("quote".to_string()), ast.clone()],
+        Vector(v, _) => return list![Sym("vec".to_string()), qq_iter(v)],
+        Hash(_, _) | Set(_, _) | Sym(_)=> return list![Sym("quote".to_string()), ast.clone()],
         _ => ast.clone(),
     }
 }
@@ -75,21 +78,28 @@ fn eval_ast(ast: &MalVal, env: &Env) -> MalRet {
             for a in v.iter() {
                 lst.push(eval(a.clone(), env.clone())?)
             }
-            Ok(list!(lst))
+            Ok(list!(lst.into()))
         }
         Vector(v, _) => {
             let mut lst: MalArgs = vec![];
             for a in v.iter() {
                 lst.push(eval(a.clone(), env.clone())?)
             }
-            Ok(vector!(lst))
+            Ok(vector!(lst.into()))
         }
         Hash(hm, _) => {
-            let mut new_hm: FnvHashMap<String, MalVal> = FnvHashMap::default();
+            let mut new_hm = MalMap::default();
             for (k, v) in hm.iter() {
-                new_hm.insert(k.to_string(), eval(v.clone(), env.clone())?);
+                new_hm.insert(k.clone(), eval(v.clone(), env.clone())?);
             }
-            Ok(Hash(Rc::new(new_hm), Rc::new(Nil)))
+            Ok(Hash(new_hm, Rc::default()))
+        }
+        Set(hs, _) => {
+            let mut lst: MalArgs = vec![];
+            for a in hs.values() {
+                lst.push(eval(a.clone(), env.clone())?)
+            }
+            hash_set(&lst)
         }
         _ => Ok(ast.clone()),
     }
@@ -143,7 +153,7 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
                         continue 'tco;
                     }
                     Sym(ref a0sym) if a0sym == "do" => {
-                        match eval_ast(&list!(l[1..l.len() - 1].to_vec()), &env)? {
+                        match eval_ast(&list!(l.skip(1).take(l.len() - 2)), &env)? {
                             List(_, _) => {
                                 ast = l.last().unwrap_or(&Nil).clone();
                                 continue 'tco;
@@ -170,9 +180,9 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
                         let (a1, a2) = (l[1].clone(), l[2].clone());
                         Ok(MalFunc {
                             eval: eval,
-                            ast: Rc::new(a2),
+                            name: None,
+                            arities: Rc::new(vec![(a1, a2)]),
                             env: env,
-                            params: Rc::new(a1),
                             is_macro: false,
                             meta: Rc::new(Nil),
                         })
@@ -187,19 +197,13 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
                     _ => match eval_ast(&ast, &env)? {
                         List(ref el, _) => {
                             let ref f = el[0].clone();
-                            let args = el[1..].to_vec();
+                            let args: MalArgs = el.iter().skip(1).cloned().collect();
                             match f {
                                 Func(_, _) => f.apply(args),
-                                MalFunc {
-                                    ast: mast,
-                                    env: menv,
-                                    params,
-                                    ..
-                                } => {
-                                    let a = &**mast;
-                                    let p = &**params;
-                                    env = env_bind(Some(menv.clone()), p.clone(), args)?;
-                                    ast = a.clone();
+                                MalFunc { .. } => {
+                                    let (a, fn_env) = f.bind_call(args)?;
+                                    env = fn_env;
+                                    ast = a;
                                     continue 'tco;
                                 }
                                 _ => error("attempt to call non-function"),
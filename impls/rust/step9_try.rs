@@ -1,12 +1,13 @@
 use std::rc::Rc;
 //use std::collections::HashMap;
-use fnv::FnvHashMap;
 use itertools::Itertools;
 
 #[macro_use]
 extern crate lazy_static;
 extern crate fnv;
 extern crate itertools;
+extern crate num_bigint;
+extern crate num_traits;
 extern crate regex;
 
 extern crate rustyline;
@@ -15,11 +16,12 @@ use rustyline::Editor;
 
 #[macro_use]
 mod types;
-use crate::types::MalErr::{ErrMalVal, ErrString};
-use crate::types::MalVal::{Bool, Func, Hash, List, MalFunc, Nil, Str, Sym, Vector};
-use crate::types::{error, format_error, MalArgs, MalErr, MalRet, MalVal};
+use crate::types::MalVal::{Bool, Func, Hash, List, MalFunc, Nil, Set, Str, Sym, Vector};
+use crate::types::{error, format_error, hash_set, MalArgs, MalErr, MalMap, MalRet, MalSeq, MalVal};
+#[allow(dead_code)]
 mod env;
 mod printer;
+#[allow(dead_code)]
 mod reader;
 use crate::env::{env_bind, env_find, env_get, env_new, env_set, env_sets, Env};
 #[macro_use]
@@ -32,7 +34,7 @@ fn read(str: &str) -> MalRet {
 
 // eval
 
-fn qq_iter(elts: &MalArgs) -> MalVal {
+fn qq_iter(elts: &MalSeq) -> MalVal {
     let mut acc = list![];
     for elt in elts.iter().rev() {
         if let List(v, _) = elt {
@@ -60,10 +62,10 @@ fn quasiquote(ast: &MalVal) -> MalVal {
                     }
                 }
             }
-            return qq_iter(&v);
+            return qq_iter(v);
         },
-        Vector(v, _) => return list![Sym("vec".to_string()), qq_iter(&v)],
-        Hash(_, _) | Sym(_)=> return list![Sym("quote".to_string()), ast.clone()],
+        Vector(v, _) => return list![Sym("vec".to_string()), qq_iter(v)],
+        Hash(_, _) | Set(_, _) | Sym(_)=> return list![Sym("quote".to_string()), ast.clone()],
         _ => ast.clone(),
     }
 }
@@ -73,7 +75,9 @@ fn is_macro_call(ast: &MalVal, env: &Env) -> Option<(MalVal, MalArgs)> {
         List(v, _) => match v[0] {
             Sym(ref s) => match env_find(env, s) {
                 Some(e) => match env_get(&e, &v[0]) {
-                    Ok(f @ MalFunc { is_macro: true, .. }) => Some((f, v[1..].to_vec())),
+                    Ok(f @ MalFunc { is_macro: true, .. }) => {
+                        Some((f, v.iter().skip(1).cloned().collect()))
+                    }
                     _ => None,
                 },
                 _ => None,
@@ -106,21 +110,28 @@ fn eval_ast(ast: &MalVal, env: &Env) -> MalRet {
             for a in v.iter() {
                 lst.push(eval(a.clone(), env.clone())?)
             }
-            Ok(list!(lst))
+            Ok(list!(lst.into()))
         }
         Vector(v, _) => {
             let mut lst: MalArgs = vec![];
             for a in v.iter() {
                 lst.push(eval(a.clone(), env.clone())?)
             }
-            Ok(vector!(lst))
+            Ok(vector!(lst.into()))
         }
         Hash(hm, _) => {
-            let mut new_hm: FnvHashMap<String, MalVal> = FnvHashMap::default();
+            let mut new_hm = MalMap::default();
             for (k, v) in hm.iter() {
-                new_hm.insert(k.to_string(), eval(v.clone(), env.clone())?);
+                new_hm.insert(k.clone(), eval(v.clone(), env.clone())?);
+            }
+            Ok(Hash(new_hm, Rc::default()))
+        }
+        Set(hs, _) => {
+            let mut lst: MalArgs = vec![];
+            for a in hs.values() {
+                lst.push(eval(a.clone(), env.clone())?)
             }
-            Ok(Hash(Rc::new(new_hm), Rc::new(Nil)))
+            hash_set(&lst)
         }
         _ => Ok(ast.clone()),
     }
@@ -191,18 +202,18 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
                         match r {
                             MalFunc {
                                 eval,
-                                ast,
+                                name,
+                                arities,
                                 env,
-                                params,
                                 ..
                             } => Ok(env_set(
                                 &env,
                                 a1.clone(),
                                 MalFunc {
                                     eval: eval,
-                                    ast: ast.clone(),
+                                    name: name.clone(),
+                                    arities: arities.clone(),
                                     env: env.clone(),
-                                    params: params.clone(),
                                     is_macro: true,
                                     meta: Rc::new(Nil),
                                 },
@@ -217,17 +228,15 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
                         }
                     }
                     Sym(ref a0sym) if a0sym == "try*" => match eval(l[1].clone(), env.clone()) {
-                        Err(ref e) if l.len() >= 3 => {
-                            let exc = match e {
-                                ErrMalVal(mv) => mv.clone(),
-                                ErrString(s) => Str(s.to_string()),
-                            };
+                        Err(e) if l.len() >= 3 => {
+                            let exc = e.exc_val();
                             match l[2].clone() {
                                 List(c, _) => {
                                     let catch_env = env_bind(
                                         Some(env.clone()),
-                                        list!(vec![c[1].clone()]),
+                                        list!(MalSeq::unit(c[1].clone())),
                                         vec![exc],
+                                        eval,
                                     )?;
                                     eval(c[2].clone(), catch_env)
                                 }
@@ -237,7 +246,7 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
                         res => res,
                     },
                     Sym(ref a0sym) if a0sym == "do" => {
-                        match eval_ast(&list!(l[1..l.len() - 1].to_vec()), &env)? {
+                        match eval_ast(&list!(l.skip(1).take(l.len() - 2)), &env)? {
                             List(_, _) => {
                                 ast = l.last().unwrap_or(&Nil).clone();
                                 continue 'tco;
@@ -264,9 +273,9 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
                         let (a1, a2) = (l[1].clone(), l[2].clone());
                         Ok(MalFunc {
                             eval: eval,
-                            ast: Rc::new(a2),
+                            name: None,
+                            arities: Rc::new(vec![(a1, a2)]),
                             env: env,
-                            params: Rc::new(a1),
                             is_macro: false,
                             meta: Rc::new(Nil),
                         })
@@ -281,19 +290,13 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
                     _ => match eval_ast(&ast, &env)? {
                         List(ref el, _) => {
                             let ref f = el[0].clone();
-                            let args = el[1..].to_vec();
+                            let args: MalArgs = el.iter().skip(1).cloned().collect();
                             match f {
                                 Func(_, _) => f.apply(args),
-                                MalFunc {
-                                    ast: mast,
-                                    env: menv,
-                                    params,
-                                    ..
-                                } => {
-                                    let a = &**mast;
-                                    let p = &**params;
-                                    env = env_bind(Some(menv.clone()), p.clone(), args)?;
-                                    ast = a.clone();
+                                MalFunc { .. } => {
+                                    let (a, fn_env) = f.bind_call(args)?;
+                                    env = fn_env;
+                                    ast = a;
                                     continue 'tco;
                                 }
                                 _ => error("attempt to call non-function"),
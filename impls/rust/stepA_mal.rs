@@ -1,29 +1,42 @@
 #![allow(non_snake_case)]
 
+use std::cell::RefCell;
 use std::rc::Rc;
+use std::io::Read;
+use std::path::Path;
+use std::time::Instant;
 //use std::collections::HashMap;
-use fnv::FnvHashMap;
 use itertools::Itertools;
 
 #[macro_use]
 extern crate lazy_static;
 extern crate fnv;
 extern crate itertools;
+extern crate num_bigint;
+extern crate num_traits;
 extern crate regex;
 
 extern crate rustyline;
 use rustyline::error::ReadlineError;
-use rustyline::Editor;
+use rustyline::{CompletionType, Config, Editor};
 
 #[macro_use]
 mod types;
-use crate::types::MalErr::{ErrMalVal, ErrString};
-use crate::types::MalVal::{Bool, Func, Hash, List, MalFunc, Nil, Str, Sym, Vector};
-use crate::types::{error, format_error, MalArgs, MalErr, MalRet, MalVal};
+use crate::types::MalVal::{
+    Bool, ExInfo, Func, Hash, Int, Keyword, List, MalFunc, Nil, Set, Str, Sym, Vector,
+};
+use crate::types::{
+    err_info, format_error, hash_key, hash_set, keyword, syntax_error, type_error, Arity, Frame,
+    MalArgs, MalErr, MalMap, MalRet, MalSeq, MalVal, Meta, SrcPos,
+};
 mod env;
 mod printer;
 mod reader;
-use crate::env::{env_bind, env_find, env_get, env_new, env_set, env_sets, Env};
+mod repl;
+use crate::env::{
+    env_bind, env_destructure, env_find, env_get, env_keys, env_new, env_set, env_sets, fn_arity,
+    Env,
+};
 #[macro_use]
 mod core;
 
@@ -34,7 +47,7 @@ fn read(str: &str) -> MalRet {
 
 // eval
 
-fn qq_iter(elts: &MalArgs) -> MalVal {
+fn qq_iter(elts: &MalSeq) -> MalVal {
     let mut acc = list![];
     for elt in elts.iter().rev() {
         if let List(v, _) = elt {
@@ -62,20 +75,22 @@ fn quasiquote(ast: &MalVal) -> MalVal {
                     }
                 }
             }
-            return qq_iter(&v);
+            return qq_iter(v);
         },
-        Vector(v, _) => return list![Sym("vec".to_string()), qq_iter(&v)],
-        Hash(_, _) | Sym(_)=> return list![Sym("quote".to_string()), ast.clone()],
+        Vector(v, _) => return list![Sym("vec".to_string()), qq_iter(v)],
+        Hash(_, _) | Set(_, _) | Sym(_)=> return list![Sym("quote".to_string()), ast.clone()],
         _ => ast.clone(),
     }
 }
 
 fn is_macro_call(ast: &MalVal, env: &Env) -> Option<(MalVal, MalArgs)> {
     match ast {
-        List(v, _) => match v[0] {
-            Sym(ref s) => match env_find(env, s) {
+        List(v, _) => match v.first() {
+            Some(Sym(ref s)) => match env_find(env, s) {
                 Some(e) => match env_get(&e, &v[0]) {
-                    Ok(f @ MalFunc { is_macro: true, .. }) => Some((f, v[1..].to_vec())),
+                    Ok(f @ MalFunc { is_macro: true, .. }) => {
+                        Some((f, v.iter().skip(1).cloned().collect()))
+                    }
                     _ => None,
                 },
                 _ => None,
@@ -100,40 +115,325 @@ fn macroexpand(mut ast: MalVal, env: &Env) -> (bool, MalRet) {
     ((was_expanded, Ok(ast)))
 }
 
+// Errors evaluating an element of a list or vector are tagged with the
+// element's position when it's a symbol, else the vector's own.
 fn eval_ast(ast: &MalVal, env: &Env) -> MalRet {
     match ast {
         Sym(_) => Ok(env_get(&env, &ast)?),
-        List(v, _) => {
+        List(v, meta) => {
             let mut lst: MalArgs = vec![];
-            for a in v.iter() {
-                lst.push(eval(a.clone(), env.clone())?)
+            for (i, a) in v.iter().enumerate() {
+                lst.push(eval(a.clone(), env.clone()).map_err(|e| e.at(meta.sym_pos(i)))?)
             }
-            Ok(list!(lst))
+            Ok(list!(lst.into()))
         }
-        Vector(v, _) => {
+        Vector(v, meta) => {
             let mut lst: MalArgs = vec![];
-            for a in v.iter() {
-                lst.push(eval(a.clone(), env.clone())?)
+            for (i, a) in v.iter().enumerate() {
+                lst.push(
+                    eval(a.clone(), env.clone())
+                        .map_err(|e| e.at(meta.sym_pos(i)).at(meta.pos.clone()))?,
+                )
             }
-            Ok(vector!(lst))
+            Ok(vector!(lst.into()))
         }
         Hash(hm, _) => {
-            let mut new_hm: FnvHashMap<String, MalVal> = FnvHashMap::default();
+            let mut new_hm = MalMap::default();
             for (k, v) in hm.iter() {
-                new_hm.insert(k.to_string(), eval(v.clone(), env.clone())?);
+                new_hm.insert(k.clone(), eval(v.clone(), env.clone())?);
             }
-            Ok(Hash(Rc::new(new_hm), Rc::new(Nil)))
+            Ok(Hash(new_hm, Rc::default()))
+        }
+        Set(hs, _) => {
+            let mut lst: MalArgs = vec![];
+            for a in hs.values() {
+                lst.push(eval(a.clone(), env.clone())?)
+            }
+            hash_set(&lst)
         }
         _ => Ok(ast.clone()),
     }
 }
 
-fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
+// The number of args each special form takes as (min, max)
+fn special_form_arity(name: &str) -> Option<(usize, usize)> {
+    match name {
+        "def!" | "let*" | "loop*" | "defmacro!" => Some((2, 2)),
+        "fn*" => Some((1, usize::MAX)),
+        "quote" | "quasiquote" | "quasiquoteexpand" | "macroexpand" | "eval" => Some((1, 1)),
+        "try*" => Some((1, usize::MAX)),
+        "if" => Some((2, 3)),
+        _ => None,
+    }
+}
+
+// The optional name and the (params, body) arities of a fn* form, which is
+// either (fn* name? params body) or (fn* name? (params body)...)
+fn fn_arities(ast: &MalVal) -> Result<(Option<Rc<str>>, Vec<Arity>), MalErr> {
+    let l = match ast {
+        List(l, _) => l,
+        _ => return Err(syntax_error("invalid fn* form")),
+    };
+    let (name, rest) = match l[1] {
+        Sym(ref name) => (Some(Rc::from(&name[..])), l.skip(2)),
+        _ => (None, l.skip(1)),
+    };
+    let is_arity = |a: &MalVal| match a {
+        List(c, _) => matches!(c.get(0), Some(List(..)) | Some(Vector(..))),
+        _ => false,
+    };
+    let arities: Vec<Arity> = match rest.get(0) {
+        Some(Vector(..)) | Some(List(..)) if rest.len() == 2 && !rest.iter().all(is_arity) => {
+            vec![(rest[0].clone(), rest[1].clone())]
+        }
+        _ if !rest.is_empty() && rest.iter().all(is_arity) => rest
+            .iter()
+            .map(|a| match a {
+                List(c, _) if c.len() == 2 => Ok((c[0].clone(), c[1].clone())),
+                _ => Err(syntax_error(&format!("invalid fn* arity {}", a.pr_str(true)))),
+            })
+            .collect::<Result<_, _>>()?,
+        _ => return Err(syntax_error("invalid fn* form")),
+    };
+    let mut fixed = vec![];
+    let mut variadic = None;
+    for (params, _) in &arities {
+        match fn_arity(params) {
+            Some((_, true)) if variadic.is_some() => {
+                return Err(syntax_error("fn* can't have more than one variadic arity"))
+            }
+            Some((n, true)) => variadic = Some(n),
+            Some((n, false)) if fixed.contains(&n) => {
+                return Err(syntax_error(&format!(
+                    "fn* can't have two arities taking {} args",
+                    n
+                )))
+            }
+            Some((n, false)) => fixed.push(n),
+            // the params were checked to be a list or vector above
+            None => (),
+        }
+    }
+    if let Some(v) = variadic {
+        if fixed.iter().any(|&n| n > v) {
+            return Err(syntax_error(
+                "fn* can't have a fixed arity with more params than the variadic one",
+            ));
+        }
+    }
+    Ok((name, arities))
+}
+
+// The innermost loop* being evaluated, which recur jumps back to
+struct LoopFrame {
+    // the env the loop* form is evaluated in
+    outer: Env,
+    binds: Vec<MalVal>,
+    // the body with its macros expanded, see check_recur
+    body: MalVal,
+}
+
+// Check that each recur in `ast` is in tail position of the enclosing
+// loop* and passes one value per loop binding. Macros are expanded first
+// so that e.g. a recur in a cond clause is found, and `ast` is returned
+// with them expanded so that the loop doesn't expand them again on every
+// iteration. Quoted forms are left alone, and the bodies of nested loop*
+// forms are checked when they are evaluated.
+fn check_recur(ast: &MalVal, env: &Env, arity: usize, tail: bool) -> MalRet {
+    let check = |a: &MalVal, tail: bool| check_recur(a, env, arity, tail);
+    let ast = match ast {
+        List(..) => macroexpand(ast.clone(), env).1?,
+        Vector(v, meta) => {
+            let v = v.iter().map(|a| check(a, false)).collect::<Result<MalArgs, _>>()?;
+            return Ok(Vector(v.into(), meta.clone()));
+        }
+        Hash(hm, meta) => {
+            let mut new_hm = MalMap::default();
+            for (k, v) in hm.iter() {
+                new_hm.insert(k.clone(), check(v, false)?);
+            }
+            return Ok(Hash(new_hm, meta.clone()));
+        }
+        Set(hs, _) => {
+            let items = hs.values().map(|a| check(a, false)).collect::<Result<MalArgs, _>>()?;
+            return hash_set(&items);
+        }
+        _ => return Ok(ast.clone()),
+    };
+    let (l, meta) = match ast {
+        List(ref l, ref meta) if !l.is_empty() => (l, meta),
+        _ => return Ok(ast),
+    };
+    // only the values of a let* or loop* binding vector are forms
+    let check_binds = |binds: &MalVal| -> MalRet {
+        let checked = |b: &MalSeq| {
+            b.iter()
+                .enumerate()
+                .map(|(i, a)| if i % 2 == 1 { check(a, false) } else { Ok(a.clone()) })
+                .collect::<Result<MalArgs, _>>()
+        };
+        Ok(match binds {
+            List(b, meta) => List(checked(b)?.into(), meta.clone()),
+            Vector(b, meta) => Vector(checked(b)?.into(), meta.clone()),
+            _ => binds.clone(),
+        })
+    };
+    let head = match l[0] {
+        Sym(ref s) => s.as_str(),
+        _ => "",
+    };
+    match head {
+        "quote" | "quasiquote" | "quasiquoteexpand" => return Ok(ast.clone()),
+        "recur" if !tail => return Err(syntax_error("can only recur from tail position")),
+        "recur" if l.len() - 1 != arity => {
+            return Err(syntax_error(&format!(
+                "mismatched arg count to recur, expected {} args, got {}",
+                arity,
+                l.len() - 1
+            )))
+        }
+        _ => (),
+    }
+    let last = l.len() - 1;
+    let mut forms: MalArgs = vec![];
+    for (i, a) in l.iter().enumerate() {
+        forms.push(match (head, i) {
+            ("if", 1) => check(a, false)?,
+            ("if", _) | ("let*", _) if i > 1 => check(a, tail)?,
+            ("do", _) => check(a, tail && i == last)?,
+            ("let*", 1) | ("loop*", 1) => check_binds(a)?,
+            ("loop*", _) => a.clone(),
+            // a recur can't cross a function or try* boundary
+            _ => check(a, false)?,
+        });
+    }
+    Ok(List(forms.into(), meta.clone()))
+}
+
+// (try* expr (catch* selector? binding handler)... (finally* form...)?)
+// An error is handled by the first catch* whose selector matches it: a
+// keyword matches error maps with that :type, :default matches anything,
+// and a function is called with the caught value. A catch* without a
+// selector matches anything too, but binds an interpreter error as its
+// message string like step9 does; a selector gets the error map.
+// Errors that no catch* matches propagate unchanged. The finally* forms
+// are evaluated for effect after the body and any handler, whether or not
+// they threw.
+fn eval_try(ast: &MalVal, l: &MalSeq, env: &Env) -> MalRet {
+    let clauses = l.skip(2);
+    let mut catches = vec![];
+    let mut finally = None;
+    let invalid = |clause: &MalVal| {
+        let msg = format!("invalid catch block {}", clause.pr_str(true));
+        Err(syntax_error(&msg).in_form(ast))
+    };
+    for (i, clause) in clauses.iter().enumerate() {
+        let c = match clause {
+            List(c, _) => c,
+            _ => return invalid(clause),
+        };
+        match c.first() {
+            Some(Sym(s)) if s == "catch*" && (c.len() == 3 || c.len() == 4) => {
+                catches.push(c.clone())
+            }
+            Some(Sym(s)) if s == "finally*" && i == clauses.len() - 1 => finally = Some(c.skip(1)),
+            Some(Sym(s)) if s == "finally*" => {
+                let msg = "finally* must be the last clause of try*";
+                return Err(syntax_error(msg).in_form(ast));
+            }
+            _ => return invalid(clause),
+        }
+    }
+    let res = match eval(l[1].clone(), env.clone()) {
+        Err(e) => catch(e, &catches, env),
+        res => res,
+    };
+    if let Some(forms) = finally {
+        for form in forms.iter() {
+            eval(form.clone(), env.clone())?;
+        }
+    }
+    res
+}
+
+// Run the handler of the first catch* clause matching `e`
+fn catch(e: MalErr, catches: &[MalSeq], env: &Env) -> MalRet {
+    let e = Rc::new(e);
+    let exc = e.exc_info().with_caught(e.clone());
+    for c in catches {
+        let val = if c.len() == 4 {
+            if !catch_matches(&c[1], &exc, env)? {
+                continue;
+            }
+            exc.clone()
+        } else {
+            e.exc_val().with_caught(e.clone())
+        };
+        let (binding, handler) = (c[c.len() - 2].clone(), c[c.len() - 1].clone());
+        let catch_env = env_bind(Some(env.clone()), list!(MalSeq::unit(binding)), vec![val], eval)?;
+        return eval(handler, catch_env);
+    }
+    // a selector may have kept hold of the caught value
+    drop(exc);
+    Err(Rc::try_unwrap(e).unwrap_or_else(|e| (*e).clone()))
+}
+
+fn catch_matches(selector: &MalVal, exc: &MalVal, env: &Env) -> Result<bool, MalErr> {
+    match eval(selector.clone(), env.clone())? {
+        Keyword(ref k) if &**k == "default" => Ok(true),
+        kind @ Keyword(_) => Ok(match exc {
+            Hash(hm, _) => hm.get(&hash_key(&keyword("type"))?) == Some(&kind),
+            ExInfo { data, .. } => match **data {
+                Hash(ref hm, _) => hm.get(&hash_key(&keyword("type"))?) == Some(&kind),
+                _ => false,
+            },
+            _ => false,
+        }),
+        pred @ Func(..) | pred @ MalFunc { .. } => {
+            Ok(!matches!(pred.apply(vec![exc.clone()])?, Bool(false) | Nil))
+        }
+        v => {
+            let msg = format!("catch* selector must be a keyword or function: {}", v.pr_str(true));
+            Err(err_info("type-error", &msg, &[("value", v)]))
+        }
+    }
+}
+
+fn eval(ast: MalVal, env: Env) -> MalRet {
+    // errors are tagged with the position of the innermost list being
+    // evaluated when they were raised, and with the last function that
+    // was tail called. Earlier tail calls don't appear in stack traces.
+    let mut pos = None;
+    let mut call = None;
+    eval_tco(ast, env, &mut pos, &mut call).map_err(|e| match call {
+        Some(frame) => e.at(pos).traced(frame),
+        None => e.at(pos),
+    })
+}
+
+// Before tail evaluating the element `idx` of a form, take its position
+// when it's a symbol
+fn set_sym_pos(pos: &mut Option<SrcPos>, meta: &Meta, idx: usize) {
+    if let Some(p) = meta.sym_pos(idx) {
+        *pos = Some(p);
+    }
+}
+
+fn eval_tco(
+    mut ast: MalVal,
+    mut env: Env,
+    pos: &mut Option<SrcPos>,
+    call: &mut Option<Frame>,
+) -> MalRet {
     let ret: MalRet;
+    let mut frame: Option<LoopFrame> = None;
 
     'tco: loop {
         ret = match ast.clone() {
-            List(l, _) => {
+            List(l, meta) => {
+                if meta.pos.is_some() {
+                    *pos = meta.pos.clone();
+                }
                 if l.len() == 0 {
                     return Ok(ast);
                 }
@@ -142,7 +442,7 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
                         ast = new_ast;
                         continue 'tco;
                     }
-                    (_, Err(e)) => return Err(e),
+                    (_, Err(e)) => return Err(e.in_form(&ast)),
                     _ => (),
                 }
 
@@ -150,37 +450,115 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
                     return Ok(ast);
                 }
                 let a0 = &l[0];
+                if let Sym(ref a0sym) = a0 {
+                    if let Some((min, max)) = special_form_arity(a0sym) {
+                        if l.len() - 1 < min || l.len() - 1 > max {
+                            let msg = format!(
+                                "wrong number of args ({}) passed to {} in {}",
+                                l.len() - 1,
+                                a0sym,
+                                ast.pr_str(true)
+                            );
+                            let nargs = Int(l.len() as i64 - 1);
+                            let context = [("name", Str(a0sym.clone())), ("args", nargs)];
+                            return Err(err_info("arity", &msg, &context));
+                        }
+                    }
+                }
                 match a0 {
                     Sym(ref a0sym) if a0sym == "def!" => {
-                        env_set(&env, l[1].clone(), eval(l[2].clone(), env.clone())?)
+                        let val =
+                            eval(l[2].clone(), env.clone()).map_err(|e| e.at(meta.sym_pos(2)))?;
+                        env_set(&env, l[1].clone(), val).map_err(|e| e.in_form(&ast))
                     }
                     Sym(ref a0sym) if a0sym == "let*" => {
                         env = env_new(Some(env.clone()));
                         let (a1, a2) = (l[1].clone(), l[2].clone());
                         match a1 {
-                            List(ref binds, _) | Vector(ref binds, _) => {
-                                for (b, e) in binds.iter().tuples() {
-                                    match b {
-                                        Sym(_) => {
-                                            let _ = env_set(
-                                                &env,
-                                                b.clone(),
-                                                eval(e.clone(), env.clone())?,
-                                            );
-                                        }
-                                        _ => {
-                                            return error("let* with non-Sym binding");
-                                        }
-                                    }
+                            List(ref binds, ref bmeta) | Vector(ref binds, ref bmeta) => {
+                                if binds.len() % 2 != 0 {
+                                    return Err(syntax_error(&format!(
+                                        "let* with odd number of binding forms in {}",
+                                        ast.pr_str(true)
+                                    )));
+                                }
+                                for (i, (b, e)) in binds.iter().tuples().enumerate() {
+                                    let val = eval(e.clone(), env.clone())
+                                        .map_err(|e| e.at(bmeta.sym_pos(2 * i + 1)))?;
+                                    env_destructure(&env, b, val, eval)
+                                        .map_err(|e| e.in_form(&ast))?;
                                 }
                             }
                             _ => {
-                                return error("let* with non-List bindings");
+                                return Err(syntax_error(&format!(
+                                    "let* with non-List bindings in {}",
+                                    ast.pr_str(true)
+                                )));
                             }
                         };
+                        set_sym_pos(pos, &meta, 2);
                         ast = a2;
                         continue 'tco;
                     }
+                    Sym(ref a0sym) if a0sym == "loop*" => {
+                        let binds = match l[1] {
+                            List(ref b, _) | Vector(ref b, _) if b.len() % 2 == 0 => b,
+                            _ => {
+                                return Err(syntax_error(&format!(
+                                    "loop* needs an even number of bindings in {}",
+                                    ast.pr_str(true)
+                                )))
+                            }
+                        };
+                        let loop_env = env_new(Some(env.clone()));
+                        for (b, e) in binds.iter().tuples() {
+                            let val = eval(e.clone(), loop_env.clone())?;
+                            env_destructure(&loop_env, b, val, eval)
+                                .map_err(|e| e.in_form(&ast))?;
+                        }
+                        let body = check_recur(&l[2], &loop_env, binds.len() / 2, true)
+                            .map_err(|e| e.in_form(&ast))?;
+                        frame = Some(LoopFrame {
+                            outer: env.clone(),
+                            binds: binds.iter().step_by(2).cloned().collect(),
+                            body: body.clone(),
+                        });
+                        env = loop_env;
+                        ast = body;
+                        continue 'tco;
+                    }
+                    Sym(ref a0sym) if a0sym == "recur" => {
+                        let f = match frame {
+                            Some(ref f) => f,
+                            None => {
+                                return Err(syntax_error("recur outside of loop*").in_form(&ast))
+                            }
+                        };
+                        if l.len() - 1 != f.binds.len() {
+                            let msg = format!(
+                                "mismatched arg count to recur, expected {} args, got {}",
+                                f.binds.len(),
+                                l.len() - 1
+                            );
+                            return Err(syntax_error(&msg).in_form(&ast));
+                        }
+                        // all the new values are computed before any is rebound
+                        let vals: MalArgs = match eval_ast(&list!(l.skip(1)), &env)? {
+                            List(vals, _) => vals.iter().cloned().collect(),
+                            _ => return Err(syntax_error("invalid recur form")),
+                        };
+                        // each iteration binds the locals in a fresh env
+                        // rather than in place, so that closures made by
+                        // earlier iterations keep the values they saw
+                        let loop_env = env_new(Some(f.outer.clone()));
+                        for (b, val) in f.binds.iter().zip(vals) {
+                            env_destructure(&loop_env, b, val, eval)
+                                .map_err(|e| e.in_form(&ast))?;
+                        }
+                        env = loop_env;
+                        ast = f.body.clone();
+                        continue 'tco;
+                    }
                     Sym(ref a0sym) if a0sym == "quote" => Ok(l[1].clone()),
                     Sym(ref a0sym) if a0sym == "quasiquoteexpand" => Ok(quasiquote(&l[1])),
                     Sym(ref a0sym) if a0sym == "quasiquote" => {
@@ -193,23 +571,24 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
                         match r {
                             MalFunc {
                                 eval,
-                                ast,
+                                name,
+                                arities,
                                 env,
-                                params,
                                 ..
                             } => Ok(env_set(
                                 &env,
                                 a1.clone(),
                                 MalFunc {
                                     eval: eval,
-                                    ast: ast.clone(),
+                                    name: name.clone(),
+                                    arities: arities.clone(),
                                     env: env.clone(),
-                                    params: params.clone(),
                                     is_macro: true,
                                     meta: Rc::new(Nil),
                                 },
                             )?),
-                            _ => error("set_macro on non-function"),
+                            _ => type_error("set_macro on non-function")
+                                .map_err(|e| e.in_form(&ast)),
                         }
                     }
                     Sym(ref a0sym) if a0sym == "macroexpand" => {
@@ -218,44 +597,29 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
                             (_, e) => return e,
                         }
                     }
-                    Sym(ref a0sym) if a0sym == "try*" => match eval(l[1].clone(), env.clone()) {
-                        Err(ref e) if l.len() >= 3 => {
-                            let exc = match e {
-                                ErrMalVal(mv) => mv.clone(),
-                                ErrString(s) => Str(s.to_string()),
-                            };
-                            match l[2].clone() {
-                                List(c, _) => {
-                                    let catch_env = env_bind(
-                                        Some(env.clone()),
-                                        list!(vec![c[1].clone()]),
-                                        vec![exc],
-                                    )?;
-                                    eval(c[2].clone(), catch_env)
-                                }
-                                _ => error("invalid catch block"),
-                            }
-                        }
-                        res => res,
-                    },
+                    Sym(ref a0sym) if a0sym == "try*" => eval_try(&ast, &l, &env),
+                    Sym(ref a0sym) if a0sym == "do" && l.len() == 1 => Ok(Nil),
                     Sym(ref a0sym) if a0sym == "do" => {
-                        match eval_ast(&list!(l[1..l.len() - 1].to_vec()), &env)? {
-                            List(_, _) => {
-                                ast = l.last().unwrap_or(&Nil).clone();
-                                continue 'tco;
-                            }
-                            _ => error("invalid do form"),
+                        let last = l.len() - 1;
+                        for (i, a) in l.iter().enumerate().take(last).skip(1) {
+                            eval(a.clone(), env.clone()).map_err(|e| e.at(meta.sym_pos(i)))?;
                         }
+                        set_sym_pos(pos, &meta, last);
+                        ast = l[last].clone();
+                        continue 'tco;
                     }
                     Sym(ref a0sym) if a0sym == "if" => {
-                        let cond = eval(l[1].clone(), env.clone())?;
+                        let cond =
+                            eval(l[1].clone(), env.clone()).map_err(|e| e.at(meta.sym_pos(1)))?;
                         match cond {
                             Bool(false) | Nil if l.len() >= 4 => {
+                                set_sym_pos(pos, &meta, 3);
                                 ast = l[3].clone();
                                 continue 'tco;
                             }
                             Bool(false) | Nil => Ok(Nil),
                             _ if l.len() >= 3 => {
+                                set_sym_pos(pos, &meta, 2);
                                 ast = l[2].clone();
                                 continue 'tco;
                             }
@@ -263,12 +627,12 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
                         }
                     }
                     Sym(ref a0sym) if a0sym == "fn*" => {
-                        let (a1, a2) = (l[1].clone(), l[2].clone());
+                        let (name, arities) = fn_arities(&ast).map_err(|e| e.in_form(&ast))?;
                         Ok(MalFunc {
                             eval: eval,
-                            ast: Rc::new(a2),
+                            name,
+                            arities: Rc::new(arities),
                             env: env,
-                            params: Rc::new(a1),
                             is_macro: false,
                             meta: Rc::new(Nil),
                         })
@@ -278,30 +642,37 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
                         while let Some(ref e) = env.clone().outer {
                             env = e.clone();
                         }
+                        frame = None;
                         continue 'tco;
                     }
                     _ => match eval_ast(&ast, &env)? {
                         List(ref el, _) => {
                             let ref f = el[0].clone();
-                            let args = el[1..].to_vec();
+                            let args: MalArgs = el.iter().skip(1).cloned().collect();
                             match f {
-                                Func(_, _) => f.apply(args),
-                                MalFunc {
-                                    ast: mast,
-                                    env: menv,
-                                    params,
-                                    ..
-                                } => {
-                                    let a = &**mast;
-                                    let p = &**params;
-                                    env = env_bind(Some(menv.clone()), p.clone(), args)?;
-                                    ast = a.clone();
+                                Func(_, _) => f.apply(args).map_err(|e| e.in_form(&ast)),
+                                MalFunc { .. } => {
+                                    let (a, fn_env) =
+                                        f.bind_call(args).map_err(|e| e.in_form(&ast))?;
+                                    *call = Some(Frame {
+                                        func: f.clone(),
+                                        form: Some(ast.clone()),
+                                        pos: pos.clone(),
+                                    });
+                                    env = fn_env;
+                                    ast = a;
+                                    frame = None;
                                     continue 'tco;
                                 }
-                                _ => error("attempt to call non-function"),
+                                _ => {
+                                    let msg =
+                                        format!("attempt to call non-function {}", f.pr_str(true));
+                                    let context = [("value", f.clone())];
+                                    Err(err_info("type-error", &msg, &context).in_form(&ast))
+                                }
                             }
                         }
-                        _ => error("expected a list"),
+                        _ => Err(syntax_error("expected a list")),
                     },
                 }
             }
@@ -325,60 +696,353 @@ fn rep(str: &str, env: &Env) -> Result<String, MalErr> {
     Ok(print(&exp))
 }
 
-fn main() {
-    let mut args = std::env::args();
-    let arg1 = args.nth(1);
+const REPL_HELP: &str = "\
+:env [prefix]  list the names bound in the REPL environment
+:doc sym       describe a special form or the value bound to sym
+:load path     load and evaluate a file
+:time expr     evaluate expr and report how long it took
+:expand form   macroexpand form and pretty print the result
+:reset         start again with a fresh environment
+:quit          leave the REPL
+:help          show this message";
 
-    // `()` can be used when no completer is required
-    let mut rl = Editor::<()>::new();
-    if rl.load_history(".mal-history").is_err() {
-        eprintln!("No previous history.");
+fn repl_doc(name: &str, env: &Env) -> Result<String, MalErr> {
+    if let Some((_, usage)) = repl::SPECIAL_FORMS.iter().find(|(n, _)| *n == name) {
+        return Ok(format!("{}\n  special form", usage));
     }
+    let val = env_get(env, &Sym(name.to_string()))?;
+    Ok(match val {
+        Func(..) => format!("{}\n  builtin function", name),
+        MalFunc {
+            ref arities,
+            is_macro,
+            ..
+        } => {
+            // one usage line for each arity
+            let usage = arities
+                .iter()
+                .map(|(params, _)| {
+                    let params = match params {
+                        List(ref p, _) | Vector(ref p, _) => {
+                            p.iter().map(|p| p.pr_str(true)).join(" ")
+                        }
+                        _ => String::new(),
+                    };
+                    format!("({} {})", name, params)
+                })
+                .join("\n");
+            let kind = if is_macro { "macro" } else { "function" };
+            format!("{}\n  {}", usage, kind)
+        }
+        _ => format!("{}\n  {}", name, val.pr_str(true)),
+    })
+}
+
+// The REPL commands other than :reset and :quit, which are handled by the
+// main loop. Returns None when `cmd` isn't a command so that the input can
+// be evaluated instead.
+fn repl_command(cmd: &str, arg: &str, env: &Env) -> Option<Result<String, MalErr>> {
+    Some(match cmd {
+        ":help" => Ok(REPL_HELP.to_string()),
+        ":env" => Ok(env_keys(env).into_iter().filter(|k| k.starts_with(arg)).join("\n")),
+        ":doc" => repl_doc(arg, env),
+        ":load" => load_file(arg.trim_matches('"'), env).map(|_| String::new()),
+        ":time" => {
+            let start = Instant::now();
+            rep(arg, env).map(|out| {
+                format!("{}\nElapsed time: {} msecs", out, start.elapsed().as_millis())
+            })
+        }
+        ":expand" => read(arg)
+            .and_then(|ast| macroexpand(ast, env).1)
+            .map(|ast| repl::pretty(&ast)),
+        _ => return None,
+    })
+}
 
+// The core functions plus those defined using the language itself
+fn repl_env_new(argv: &[String]) -> Env {
     // core.rs: defined using rust
     let repl_env = env_new(None);
     for (k, v) in core::ns() {
         env_sets(&repl_env, k, v);
     }
-    env_sets(&repl_env, "*ARGV*", list!(args.map(Str).collect()));
+    env_sets(&repl_env, "*ARGV*", list!(argv.iter().cloned().map(Str).collect()));
+    env_sets(&repl_env, "load-file", types::func(load_file_builtin));
+    REPL_ENV.with(|e| *e.borrow_mut() = Some(repl_env.clone()));
 
     // core.mal: defined using the language itself
     let _ = rep("(def! *host-language* \"rust\")", &repl_env);
     let _ = rep("(def! not (fn* (a) (if a false true)))", &repl_env);
-    let _ = rep(
-        "(def! load-file (fn* (f) (eval (read-string (str \"(do \" (slurp f) \"\nnil)\")))))",
-        &repl_env,
-    );
     let _ = rep("(defmacro! cond (fn* (& xs) (if (> (count xs) 0) (list 'if (first xs) (if (> (count xs) 1) (nth xs 1) (throw \"odd number of forms to cond\")) (cons 'cond (rest (rest xs)))))))", &repl_env);
+    repl_env
+}
+
+const USAGE: &str = "\
+Usage: mal [options] [file | -] [args...]
+
+Runs the script `file`, or standard input for `-`, with `args` bound to
+*ARGV*. Starts a REPL when there is no script.
+
+Options:
+  -e EXPR       evaluate EXPR and print the result; when repeated each
+                EXPR is evaluated in turn
+  -i FILE       load FILE, then start the REPL
+  --no-history  don't read or write the REPL history
+  --help        show this message
+  --version     show the version
+
+Before starting the REPL ~/.malrc is loaded if it exists. The history is
+kept in $MAL_HISTORY, ./.mal-history if it exists, or else
+$XDG_STATE_HOME/mal/history. $MAL_HISTORY_SIZE sets how many entries are
+kept (1000 by default) and an empty $MAL_HISTORY turns history off.";
+
+#[derive(Default)]
+struct Options {
+    eval: Vec<String>,
+    init: Option<String>,
+    script: Option<String>,
+    argv: Vec<String>,
+    no_history: bool,
+}
+
+fn parse_args<I: Iterator<Item = String>>(mut args: I) -> Result<Options, String> {
+    let mut opts = Options::default();
+    while let Some(arg) = args.next() {
+        match &arg[..] {
+            "-e" => opts.eval.push(args.next().ok_or("missing expression after -e")?),
+            "-i" => opts.init = Some(args.next().ok_or("missing file after -i")?),
+            "--no-history" => opts.no_history = true,
+            "--help" => {
+                println!("{}", USAGE);
+                std::process::exit(0);
+            }
+            "--version" => {
+                println!("mal [rust] {}", env!("CARGO_PKG_VERSION"));
+                std::process::exit(0);
+            }
+            "--" => {
+                opts.script = args.next();
+                break;
+            }
+            _ if arg.starts_with('-') && arg != "-" => {
+                return Err(format!("unknown option '{}'", arg));
+            }
+            _ => {
+                opts.script = Some(arg);
+                break;
+            }
+        }
+    }
+    opts.argv = args.collect();
+    Ok(opts)
+}
+
+// Evaluate each form in a list read by read_all in turn, returning the
+// last result. A form that is a symbol has its position recorded by the
+// list.
+fn eval_forms(forms: MalVal, env: &Env) -> MalRet {
+    let mut res = Nil;
+    if let List(l, meta) = forms {
+        for (i, form) in l.iter().enumerate() {
+            res = eval(form.clone(), env.clone()).map_err(|e| e.at(meta.sym_pos(i)))?;
+        }
+    }
+    Ok(res)
+}
+
+fn load_file(path: &str, env: &Env) -> MalRet {
+    eval_forms(core::load_forms(path.to_string())?, env)?;
+    Ok(Nil)
+}
+
+thread_local! {
+    // The environment that the load-file builtin evaluates files in
+    static REPL_ENV: RefCell<Option<Env>> = const { RefCell::new(None) };
+}
+
+// load-file is native rather than defined in mal so that stack traces
+// only show the calls made by the file itself
+fn load_file_builtin(a: MalArgs) -> MalRet {
+    if a.len() != 1 {
+        return Err(types::wrong_args(a.len(), "load-file"));
+    }
+    let env = REPL_ENV.with(|e| e.borrow().clone());
+    match (&a[0], env) {
+        (Str(path), Some(env)) => load_file(path, &env),
+        (Str(_), None) => Err(MalErr::ErrString("load-file: no REPL environment".to_string())),
+        _ => type_error("load-file: expecting a file name"),
+    }
+}
+
+// Evaluate every form in `str`, returning the last result
+fn rep_all(str: &str, file: &str, env: &Env) -> Result<String, MalErr> {
+    let forms = reader::read_all(reader::skip_shebang(str).to_string(), Some(file))?;
+    Ok(print(&eval_forms(forms, env)?))
+}
+
+// Evaluate and print each form of a complete REPL input in turn. An
+// error is printed and the forms after it are still evaluated, as if they
+// had been entered one per line.
+fn rep_each(str: &str, env: &Env) {
+    let forms = match reader::read_all(str.to_string(), None) {
+        Ok(List(l, _)) => l,
+        Ok(_) => return,
+        Err(e) => return print_error(e),
+    };
+    for form in forms.iter() {
+        match eval(form.clone(), env.clone()) {
+            Ok(res) => println!("{}", print(&res)),
+            Err(e) => print_error(e),
+        }
+    }
+}
+
+// The most calls of a stack trace that are printed
+const TRACE_LIMIT: usize = 20;
+
+fn print_error(e: MalErr) {
+    let trace = e.trace();
+    let mut frames: Vec<String> =
+        trace.iter().take(TRACE_LIMIT).map(|f| format!("  at {}", f)).collect();
+    if trace.len() > TRACE_LIMIT {
+        frames.push(format!("  ... {} more", trace.len() - TRACE_LIMIT));
+    }
+    println!("Error: {}", format_error(e));
+    for frame in frames {
+        println!("{}", frame);
+    }
+}
 
-    // Invoked with arguments
-    if let Some(f) = arg1 {
-        match rep(&format!("(load-file \"{}\")", f), &repl_env) {
-            Ok(_) => std::process::exit(0),
-            Err(e) => {
-                println!("Error: {}", format_error(e));
+fn exit_on_error<T>(res: Result<T, MalErr>) -> T {
+    res.unwrap_or_else(|e| {
+        print_error(e);
+        std::process::exit(1)
+    })
+}
+
+fn env_var(name: &str) -> Option<std::ffi::OsString> {
+    std::env::var_os(name)
+}
+
+fn main() {
+    let opts = parse_args(std::env::args().skip(1)).unwrap_or_else(|e| {
+        eprintln!("mal: {}\n{}", e, USAGE);
+        std::process::exit(2)
+    });
+    let mut repl_env = repl_env_new(&opts.argv);
+
+    let interactive = opts.script.is_none() && (opts.eval.is_empty() || opts.init.is_some());
+    if let Some(rc) = repl::init_file(env_var).filter(|_| interactive) {
+        if let Err(e) = load_file(&rc.to_string_lossy(), &repl_env) {
+            print_error(e);
+        }
+    }
+    if let Some(ref f) = opts.init {
+        if let Err(e) = load_file(f, &repl_env) {
+            print_error(e);
+        }
+    }
+    for expr in &opts.eval {
+        println!("{}", exit_on_error(rep_all(expr, "<expr>", &repl_env)));
+    }
+    match opts.script.as_deref() {
+        Some("-") => {
+            let mut src = String::new();
+            if let Err(e) = std::io::stdin().read_to_string(&mut src) {
+                eprintln!("mal: {}", e);
                 std::process::exit(1);
             }
+            exit_on_error(rep_all(&src, "<stdin>", &repl_env));
+            std::process::exit(0);
+        }
+        Some(f) => {
+            exit_on_error(load_file(f, &repl_env));
+            std::process::exit(0);
+        }
+        None if !interactive => std::process::exit(0),
+        None => {}
+    }
+
+    let config = Config::builder()
+        .completion_type(CompletionType::List)
+        .max_history_size(repl::history_size(env_var))
+        .history_ignore_dups(true)
+        .build();
+    let mut rl = Editor::with_config(config);
+    rl.set_helper(Some(repl::MalHelper::new(repl_env.clone())));
+    let mut history = if opts.no_history {
+        None
+    } else {
+        repl::history_path(env_var, Path::new(""))
+    };
+    if let Some(ref path) = history {
+        if rl.load_history(path).is_err() {
+            eprintln!("No previous history.");
         }
     }
 
     // main repl loop
     let _ = rep("(println (str \"Mal [\" *host-language* \"]\"))", &repl_env);
+    // lines are accumulated in `input` until they make up whole forms
+    let mut input = String::new();
     loop {
-        let readline = rl.readline("user> ");
-        match readline {
+        let prompt = if input.is_empty() { "user> " } else { "  ... " };
+        match rl.readline(prompt) {
             Ok(line) => {
-                rl.add_history_entry(&line);
-                rl.save_history(".mal-history").unwrap();
-                if line.len() > 0 {
-                    match rep(&line, &repl_env) {
-                        Ok(out) => println!("{}", out),
-                        Err(e) => println!("Error: {}", format_error(e)),
+                if !input.is_empty() {
+                    input.push('\n');
+                }
+                // without a terminal rustyline keeps the line ending
+                input.push_str(line.trim_end_matches(['\n', '\r']));
+                if reader::is_incomplete(&input) {
+                    continue;
+                }
+                let form = std::mem::take(&mut input);
+                rl.add_history_entry(reader::one_line(&form));
+                if let Some(path) = history.take() {
+                    // warn once and carry on without saving
+                    match repl::save_history(&rl, &path) {
+                        Ok(_) => history = Some(path),
+                        Err(e) => eprintln!("mal: can't save history to {:?}: {}", path, e),
+                    }
+                }
+                if form.trim().is_empty() {
+                    continue;
+                }
+                let (cmd, arg) = match form.trim().split_once(char::is_whitespace) {
+                    Some((cmd, arg)) => (cmd, arg.trim()),
+                    None => (form.trim(), ""),
+                };
+                let res = match cmd {
+                    ":quit" => break,
+                    ":reset" => {
+                        repl_env = repl_env_new(&opts.argv);
+                        if let Some(helper) = rl.helper_mut() {
+                            helper.env = repl_env.clone();
+                        }
+                        continue;
                     }
+                    _ => match repl_command(cmd, arg, &repl_env) {
+                        Some(res) => res,
+                        None => {
+                            rep_each(&form, &repl_env);
+                            continue;
+                        }
+                    },
+                };
+                match res {
+                    Ok(out) if out.is_empty() => {}
+                    Ok(out) => println!("{}", out),
+                    Err(e) => print_error(e),
                 }
             }
-            Err(ReadlineError::Interrupted) => continue,
-            Err(ReadlineError::Eof) => break,
+            // ctrl-c abandons a partly entered form
+            Err(ReadlineError::Interrupted) => input.clear(),
+            Err(ReadlineError::Eof) => {
+                rep_each(&input, &repl_env);
+                break;
+            }
             Err(err) => {
                 println!("Error: {:?}", err);
                 break;
@@ -386,3 +1050,55 @@ fn main() {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Options, String> {
+        parse_args(args.iter().map(|s| s.to_string()))
+    }
+
+    #[test]
+    fn parses_options() {
+        let opts = parse(&["-e", "(+ 1 2)", "-i", "init.mal", "--no-history"]).unwrap();
+        assert_eq!(opts.eval, vec!["(+ 1 2)"]);
+        assert_eq!(opts.init.as_deref(), Some("init.mal"));
+        assert!(opts.no_history);
+        assert_eq!(opts.script, None);
+
+        let opts = parse(&[]).unwrap();
+        assert!(opts.eval.is_empty() && opts.init.is_none() && !opts.no_history);
+    }
+
+    #[test]
+    fn keeps_every_expression_in_order() {
+        let opts = parse(&["-e", "(def! a 1)", "-e", "(+ a 1)"]).unwrap();
+        assert_eq!(opts.eval, vec!["(def! a 1)", "(+ a 1)"]);
+    }
+
+    #[test]
+    fn script_takes_the_rest_of_the_arguments() {
+        let opts = parse(&["--no-history", "f.mal", "-e", "x", "--", "y"]).unwrap();
+        assert_eq!(opts.script.as_deref(), Some("f.mal"));
+        assert_eq!(opts.argv, vec!["-e", "x", "--", "y"]);
+        assert!(opts.eval.is_empty());
+
+        let opts = parse(&["-", "a"]).unwrap();
+        assert_eq!(opts.script.as_deref(), Some("-"));
+        assert_eq!(opts.argv, vec!["a"]);
+
+        // -- ends the options, so a script can start with a dash
+        let opts = parse(&["--", "-f.mal", "a"]).unwrap();
+        assert_eq!(opts.script.as_deref(), Some("-f.mal"));
+        assert_eq!(opts.argv, vec!["a"]);
+    }
+
+    #[test]
+    fn rejects_bad_arguments() {
+        assert_eq!(parse(&["-x"]).err().as_deref(), Some("unknown option '-x'"));
+        let err = parse(&["-e"]).err();
+        assert_eq!(err.as_deref(), Some("missing expression after -e"));
+        assert_eq!(parse(&["-i"]).err().as_deref(), Some("missing file after -i"));
+    }
+}
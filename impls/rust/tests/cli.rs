@@ -0,0 +1,100 @@
+// Runs the stepA binary the way a user would, through a pipe or with
+// command line arguments, from the crate directory.
+
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Output, Stdio};
+
+fn mal(args: &[&str], stdin: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_stepA_mal"))
+        .args(args)
+        // keep ~/.malrc and the history of whoever runs the tests out of it
+        .env("HOME", "tests/no-such-home")
+        .env("MAL_HISTORY", "")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("can't run stepA_mal");
+    child.stdin.take().unwrap().write_all(stdin.as_bytes()).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+// Write `src` to a script file of its own in the temporary directory
+fn script(name: &str, src: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("mal-cli-{}-{}.mal", name, std::process::id()));
+    fs::write(&path, src).unwrap();
+    path
+}
+
+fn run_script(name: &str, src: &str, args: &[&str]) -> Output {
+    let path = script(name, src);
+    let mut argv = vec![path.to_str().unwrap()];
+    argv.extend_from_slice(args);
+    let out = mal(&argv, "");
+    fs::remove_file(&path).unwrap();
+    out
+}
+
+fn stdout(out: &Output) -> String {
+    String::from_utf8_lossy(&out.stdout).replace("\r\n", "\n")
+}
+
+#[test]
+fn repl_evaluates_every_form_on_a_line() {
+    let out = mal(&[], "(def! a 1) (def! b (+ a 1))\nb\n");
+    assert_eq!(stdout(&out), "Mal [rust]\n1\n2\n2\n");
+}
+
+#[test]
+fn repl_continues_forms_over_lines() {
+    let out = mal(&[], "(def! a 1) (def! b\n2)\nb\n(str \"x\n y\")\n");
+    assert_eq!(stdout(&out), "Mal [rust]\n1\n2\n2\n\"x\\n y\"\n");
+}
+
+#[test]
+fn repl_keeps_going_after_an_error() {
+    let out = mal(&[], "(abc) (+ 1 2)\n(1\n");
+    assert_eq!(
+        stdout(&out),
+        "Mal [rust]\nError: 'abc' not found\n3\nError: expected ')', got EOF\n"
+    );
+}
+
+#[test]
+fn script_exits_with_the_code_passed_to_exit() {
+    let out = run_script("exit", "(println \"before\")\n(exit 3)\n(println \"after\")\n", &[]);
+    assert_eq!(out.status.code(), Some(3));
+    assert_eq!(stdout(&out), "before\n");
+}
+
+#[test]
+fn script_exits_with_1_on_an_uncaught_error() {
+    let out = run_script("error", "(println \"before\")\n(throw \"boom\")\n", &[]);
+    assert_eq!(out.status.code(), Some(1));
+    let out = stdout(&out);
+    assert!(out.starts_with("before\nError: "), "{}", out);
+    assert!(out.ends_with(":2:1: \"boom\"\n"), "{}", out);
+}
+
+#[test]
+fn script_skips_its_shebang_line_and_gets_its_arguments() {
+    let out = run_script("shebang", "#!/usr/bin/env mal\n(prn *ARGV*)\n", &["a", "-e"]);
+    assert_eq!(out.status.code(), Some(0));
+    assert_eq!(stdout(&out), "(\"a\" \"-e\")\n");
+}
+
+#[test]
+fn script_from_standard_input() {
+    let out = mal(&["-", "a"], "(prn *ARGV*)\n(exit 4)\n");
+    assert_eq!(out.status.code(), Some(4));
+    assert_eq!(stdout(&out), "(\"a\")\n");
+}
+
+#[test]
+fn evaluates_every_expression_in_order() {
+    let out = mal(&["-e", "(def! a 1)", "-e", "(+ a 1)"], "");
+    assert_eq!(out.status.code(), Some(0));
+    assert_eq!(stdout(&out), "1\n2\n");
+}
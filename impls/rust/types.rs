@@ -1,64 +1,630 @@
 use std::cell::RefCell;
+use std::fmt;
+use std::hash::{BuildHasherDefault, Hash as StdHash, Hasher};
+use std::iter::FromIterator;
+use std::ops::Index;
 use std::rc::Rc;
 //use std::collections::HashMap;
-use fnv::FnvHashMap;
+use fnv::{FnvHashMap, FnvHasher};
 use itertools::Itertools;
+use num_traits::ToPrimitive;
 
-use crate::env::{env_bind, Env};
-use crate::types::MalErr::{ErrMalVal, ErrString};
-use crate::types::MalVal::{Atom, Bool, Func, Hash, Int, List, MalFunc, Nil, Str, Sym, Vector};
+use crate::env::{env_bind, env_sets_new, fn_arity, Env};
+use crate::types::MalErr::{ErrAt, ErrInfo, ErrMalVal, ErrString, ErrTrace};
+use crate::types::MalVal::{
+    Atom, BigInt, Bool, Char, ExInfo, Float, Func, Hash, Int, Keyword, List, MalFunc, Nil, Set,
+    Str, Sym, Vector,
+};
 
 #[derive(Debug, Clone)]
 pub enum MalVal {
     Nil,
     Bool(bool),
     Int(i64),
-    //Float(f64),
+    // only used for integers outside the i64 range, see big_int
+    BigInt(Rc<num_bigint::BigInt>),
+    Float(f64),
     Str(String),
+    Char(char),
+    // interned, see keyword
+    Keyword(Rc<str>),
     Sym(String),
-    List(Rc<Vec<MalVal>>, Rc<MalVal>),
-    Vector(Rc<Vec<MalVal>>, Rc<MalVal>),
-    Hash(Rc<FnvHashMap<String, MalVal>>, Rc<MalVal>),
+    List(MalSeq, Rc<Meta>),
+    Vector(MalSeq, Rc<Meta>),
+    Hash(MalMap, Rc<Meta>),
+    // each element is mapped to itself, see hash_set
+    Set(MalMap, Rc<Meta>),
     Func(fn(MalArgs) -> MalRet, Rc<MalVal>),
     MalFunc {
         eval: fn(ast: MalVal, env: Env) -> MalRet,
-        ast: Rc<MalVal>,
+        // bound to the function itself in its body, and used when printing
+        name: Option<Rc<str>>,
+        // see select_arity
+        arities: Rc<Vec<Arity>>,
         env: Env,
-        params: Rc<MalVal>,
         is_macro: bool,
         meta: Rc<MalVal>,
     },
     Atom(Rc<RefCell<MalVal>>),
+    // made by ex-info; data is always a hash-map
+    ExInfo {
+        message: String,
+        data: Rc<MalVal>,
+        meta: Rc<Meta>,
+    },
+}
+
+// Lists, vectors and hash-maps are persistent: a modified copy shares
+// structure with the original, so conj/rest/assoc don't copy the whole
+// collection.
+//
+// Sequences of up to SMALL_SEQ elements are a plain shared Vec, which is
+// much cheaper to build and index than an RRB vector (whose smallest node
+// holds 64 values). Copying one is bounded by SMALL_SEQ, and anything
+// larger is kept in an RRB vector. Hash-maps likewise start out as a
+// shared Vec of up to SMALL_MAP entries searched linearly, then move to a
+// HAMT.
+const SMALL_SEQ: usize = 32;
+const SMALL_MAP: usize = 8;
+
+#[derive(Debug, Clone)]
+pub enum MalSeq {
+    Small(Rc<Vec<MalVal>>),
+    Large(Rc<im_rc::Vector<MalVal>>),
+}
+
+pub enum MalSeqIter<'a> {
+    Small(std::slice::Iter<'a, MalVal>),
+    Large(im_rc::vector::Iter<'a, MalVal>),
+}
+
+impl MalSeq {
+    pub fn new() -> MalSeq {
+        MalSeq::Small(Rc::new(vec![]))
+    }
+
+    pub fn unit(v: MalVal) -> MalSeq {
+        MalSeq::Small(Rc::new(vec![v]))
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            MalSeq::Small(v) => v.len(),
+            MalSeq::Large(v) => v.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn get(&self, i: usize) -> Option<&MalVal> {
+        match self {
+            MalSeq::Small(v) => v.get(i),
+            MalSeq::Large(v) => v.get(i),
+        }
+    }
+
+    pub fn first(&self) -> Option<&MalVal> {
+        self.get(0)
+    }
+
+    pub fn last(&self) -> Option<&MalVal> {
+        match self {
+            MalSeq::Small(v) => v.last(),
+            MalSeq::Large(v) => v.last(),
+        }
+    }
+
+    pub fn iter(&self) -> MalSeqIter<'_> {
+        match self {
+            MalSeq::Small(v) => MalSeqIter::Small(v.iter()),
+            MalSeq::Large(v) => MalSeqIter::Large(v.iter()),
+        }
+    }
+
+    // The elements after the first n.
+    pub fn skip(&self, n: usize) -> MalSeq {
+        match self {
+            MalSeq::Small(v) => MalSeq::Small(Rc::new(v[n.min(v.len())..].to_vec())),
+            MalSeq::Large(v) => MalSeq::Large(Rc::new(v.skip(n.min(v.len())))),
+        }
+    }
+
+    // The first n elements.
+    pub fn take(&self, n: usize) -> MalSeq {
+        match self {
+            MalSeq::Small(v) => MalSeq::Small(Rc::new(v[..n.min(v.len())].to_vec())),
+            MalSeq::Large(v) => MalSeq::Large(Rc::new(v.take(n.min(v.len())))),
+        }
+    }
+
+    fn large(&mut self) -> &mut im_rc::Vector<MalVal> {
+        if let MalSeq::Small(v) = self {
+            *self = MalSeq::Large(Rc::new(im_rc::Vector::from(&**v)));
+        }
+        match self {
+            MalSeq::Large(v) => Rc::make_mut(v),
+            MalSeq::Small(_) => unreachable!(),
+        }
+    }
+
+    pub fn push_front(&mut self, mv: MalVal) {
+        match self {
+            MalSeq::Small(v) if v.len() < SMALL_SEQ => Rc::make_mut(v).insert(0, mv),
+            _ => self.large().push_front(mv),
+        }
+    }
+
+    pub fn push_back(&mut self, mv: MalVal) {
+        match self {
+            MalSeq::Small(v) if v.len() < SMALL_SEQ => Rc::make_mut(v).push(mv),
+            _ => self.large().push_back(mv),
+        }
+    }
+
+    pub fn append(&mut self, other: MalSeq) {
+        if self.len() + other.len() <= SMALL_SEQ {
+            if let (MalSeq::Small(v), MalSeq::Small(o)) = (&mut *self, &other) {
+                Rc::make_mut(v).extend_from_slice(o);
+                return;
+            }
+        }
+        let other = match other {
+            MalSeq::Small(o) => im_rc::Vector::from(&*o),
+            MalSeq::Large(o) => (*o).clone(),
+        };
+        self.large().append(other);
+    }
+}
+
+impl Default for MalSeq {
+    fn default() -> MalSeq {
+        MalSeq::new()
+    }
+}
+
+impl From<Vec<MalVal>> for MalSeq {
+    fn from(v: Vec<MalVal>) -> MalSeq {
+        if v.len() <= SMALL_SEQ {
+            MalSeq::Small(Rc::new(v))
+        } else {
+            MalSeq::Large(Rc::new(im_rc::Vector::from(v)))
+        }
+    }
+}
+
+impl FromIterator<MalVal> for MalSeq {
+    fn from_iter<I: IntoIterator<Item = MalVal>>(iter: I) -> MalSeq {
+        MalSeq::from(iter.into_iter().collect::<Vec<MalVal>>())
+    }
+}
+
+impl Extend<MalVal> for MalSeq {
+    fn extend<I: IntoIterator<Item = MalVal>>(&mut self, iter: I) {
+        for mv in iter {
+            self.push_back(mv);
+        }
+    }
+}
+
+impl Index<usize> for MalSeq {
+    type Output = MalVal;
+
+    fn index(&self, i: usize) -> &MalVal {
+        match self {
+            MalSeq::Small(v) => &v[i],
+            MalSeq::Large(v) => &v[i],
+        }
+    }
+}
+
+impl PartialEq for MalSeq {
+    fn eq(&self, other: &MalSeq) -> bool {
+        self.len() == other.len() && self.iter().eq(other.iter())
+    }
+}
+
+impl<'a> IntoIterator for &'a MalSeq {
+    type Item = &'a MalVal;
+    type IntoIter = MalSeqIter<'a>;
+
+    fn into_iter(self) -> MalSeqIter<'a> {
+        self.iter()
+    }
+}
+
+impl<'a> Iterator for MalSeqIter<'a> {
+    type Item = &'a MalVal;
+
+    fn next(&mut self) -> Option<&'a MalVal> {
+        match self {
+            MalSeqIter::Small(it) => it.next(),
+            MalSeqIter::Large(it) => it.next(),
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        match self {
+            MalSeqIter::Small(it) => it.size_hint(),
+            MalSeqIter::Large(it) => it.size_hint(),
+        }
+    }
+}
+
+impl<'a> DoubleEndedIterator for MalSeqIter<'a> {
+    fn next_back(&mut self) -> Option<&'a MalVal> {
+        match self {
+            MalSeqIter::Small(it) => it.next_back(),
+            MalSeqIter::Large(it) => it.next_back(),
+        }
+    }
+}
+
+type Hamt = im_rc::HashMap<MapKey, MalVal, BuildHasherDefault<FnvHasher>>;
+
+#[derive(Debug, Clone)]
+pub enum MalMap {
+    Small(Rc<Vec<(MapKey, MalVal)>>),
+    Large(Rc<Hamt>),
+}
+
+pub enum MalMapIter<'a> {
+    Small(std::slice::Iter<'a, (MapKey, MalVal)>),
+    Large(im_rc::hashmap::Iter<'a, MapKey, MalVal>),
+}
+
+impl MalMap {
+    pub fn len(&self) -> usize {
+        match self {
+            MalMap::Small(v) => v.len(),
+            MalMap::Large(hm) => hm.len(),
+        }
+    }
+
+    pub fn get(&self, k: &MapKey) -> Option<&MalVal> {
+        match self {
+            MalMap::Small(v) => v.iter().find(|(vk, _)| vk == k).map(|(_, mv)| mv),
+            MalMap::Large(hm) => hm.get(k),
+        }
+    }
+
+    pub fn contains_key(&self, k: &MapKey) -> bool {
+        self.get(k).is_some()
+    }
+
+    pub fn insert(&mut self, k: MapKey, mv: MalVal) {
+        match self {
+            MalMap::Small(v) => match v.iter().position(|(vk, _)| *vk == k) {
+                Some(i) => Rc::make_mut(v)[i].1 = mv,
+                None if v.len() < SMALL_MAP => Rc::make_mut(v).push((k, mv)),
+                None => {
+                    let mut hm: Hamt = v.iter().cloned().collect();
+                    hm.insert(k, mv);
+                    *self = MalMap::Large(Rc::new(hm));
+                }
+            },
+            MalMap::Large(hm) => {
+                Rc::make_mut(hm).insert(k, mv);
+            }
+        }
+    }
+
+    pub fn remove(&mut self, k: &MapKey) {
+        match self {
+            MalMap::Small(v) => {
+                if let Some(i) = v.iter().position(|(vk, _)| vk == k) {
+                    Rc::make_mut(v).remove(i);
+                }
+            }
+            MalMap::Large(hm) => {
+                Rc::make_mut(hm).remove(k);
+            }
+        }
+    }
+
+    pub fn iter(&self) -> MalMapIter<'_> {
+        match self {
+            MalMap::Small(v) => MalMapIter::Small(v.iter()),
+            MalMap::Large(hm) => MalMapIter::Large(hm.iter()),
+        }
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &MapKey> {
+        self.iter().map(|(k, _)| k)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &MalVal> {
+        self.iter().map(|(_, mv)| mv)
+    }
+}
+
+impl Default for MalMap {
+    fn default() -> MalMap {
+        MalMap::Small(Rc::new(vec![]))
+    }
+}
+
+impl PartialEq for MalMap {
+    fn eq(&self, other: &MalMap) -> bool {
+        self.len() == other.len() && self.iter().all(|(k, mv)| other.get(k) == Some(mv))
+    }
+}
+
+impl<'a> Iterator for MalMapIter<'a> {
+    type Item = (&'a MapKey, &'a MalVal);
+
+    fn next(&mut self) -> Option<(&'a MapKey, &'a MalVal)> {
+        match self {
+            MalMapIter::Small(it) => it.next().map(|(k, mv)| (k, mv)),
+            MalMapIter::Large(it) => it.next(),
+        }
+    }
+}
+
+// A hash-map key. Hashing and equality follow PartialEq for MalVal, so
+// e.g. a list and a vector with equal elements are the same key.
+#[derive(Debug, Clone)]
+pub struct MapKey(MalVal);
+
+impl MapKey {
+    pub fn to_mal(&self) -> MalVal {
+        self.0.clone()
+    }
+}
+
+// Functions, atoms and NaN never compare equal, so they can't be looked
+// up.
+pub fn hash_key(k: &MalVal) -> Result<MapKey, MalErr> {
+    if hashable(k) {
+        Ok(MapKey(k.clone()))
+    } else {
+        Err(err_info("type-error", "key is not hashable", &[]))
+    }
 }
 
-#[derive(Debug)]
+fn hashable(v: &MalVal) -> bool {
+    match v {
+        Func(..) | MalFunc { .. } | Atom(_) => false,
+        Float(f) => !f.is_nan(),
+        List(l, _) | Vector(l, _) => l.iter().all(hashable),
+        Hash(hm, _) => hm.values().all(hashable),
+        ExInfo { data, .. } => hashable(data),
+        // set elements are keys, so they were checked when added
+        _ => true,
+    }
+}
+
+impl PartialEq for MapKey {
+    fn eq(&self, other: &MapKey) -> bool {
+        self.0 == other.0
+    }
+}
+
+impl Eq for MapKey {}
+
+impl StdHash for MapKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        hash_val(&self.0, state)
+    }
+}
+
+fn hash_val<H: Hasher>(v: &MalVal, state: &mut H) {
+    match v {
+        Nil => state.write_u8(0),
+        Bool(b) => {
+            state.write_u8(1);
+            b.hash(state);
+        }
+        Int(i) => {
+            state.write_u8(2);
+            i.hash(state);
+        }
+        BigInt(b) => match b.to_i64() {
+            Some(i) => hash_val(&Int(i), state),
+            None => {
+                state.write_u8(2);
+                b.hash(state);
+            }
+        },
+        Float(f) => {
+            state.write_u8(3);
+            // 0.0 and -0.0 are equal
+            let f = if *f == 0.0 { 0.0 } else { *f };
+            f.to_bits().hash(state);
+        }
+        Str(s) => {
+            state.write_u8(4);
+            s.hash(state);
+        }
+        Char(c) => {
+            state.write_u8(11);
+            c.hash(state);
+        }
+        Keyword(k) => {
+            state.write_u8(5);
+            k.hash(state);
+        }
+        Sym(s) => {
+            state.write_u8(6);
+            s.hash(state);
+        }
+        List(l, _) | Vector(l, _) => {
+            state.write_u8(7);
+            state.write_usize(l.len());
+            for e in l.iter() {
+                hash_val(e, state);
+            }
+        }
+        Hash(hm, _) => {
+            // entries are combined so that iteration order doesn't matter
+            state.write_u8(8);
+            let mut sum: u64 = 0;
+            for (k, v) in hm.iter() {
+                let mut h = FnvHasher::default();
+                hash_val(&k.0, &mut h);
+                hash_val(v, &mut h);
+                sum = sum.wrapping_add(h.finish());
+            }
+            state.write_u64(sum);
+        }
+        Set(hs, _) => {
+            state.write_u8(9);
+            let mut sum: u64 = 0;
+            for k in hs.keys() {
+                let mut h = FnvHasher::default();
+                hash_val(&k.0, &mut h);
+                sum = sum.wrapping_add(h.finish());
+            }
+            state.write_u64(sum);
+        }
+        Func(..) | MalFunc { .. } | Atom(_) => state.write_u8(10),
+        ExInfo { message, data, .. } => {
+            state.write_u8(12);
+            message.hash(state);
+            hash_val(data, state);
+        }
+    }
+}
+
+// Where a form was read from. `file` is only known for forms read via
+// load-file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SrcPos {
+    pub file: Option<Rc<String>>,
+    pub line: usize,
+    pub col: usize,
+}
+
+impl fmt::Display for SrcPos {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.file {
+            Some(ref file) => write!(f, "{}:{}:{}", file, self.line, self.col),
+            None => write!(f, "{}:{}", self.line, self.col),
+        }
+    }
+}
+
+// Collection metadata: the user visible value set by with-meta plus the
+// source position recorded by the reader. Symbols can't carry a position
+// themselves, so a list or vector records those of its symbol elements.
+// A value bound by catch* also keeps the error it was caught from.
+#[derive(Debug, Clone)]
+pub struct Meta {
+    pub val: MalVal,
+    pub pos: Option<SrcPos>,
+    pub sym_pos: Option<Rc<Vec<Option<SrcPos>>>>,
+    pub caught: Option<Rc<MalErr>>,
+}
+
+impl Default for Meta {
+    fn default() -> Meta {
+        Meta {
+            val: Nil,
+            pos: None,
+            sym_pos: None,
+            caught: None,
+        }
+    }
+}
+
+impl Meta {
+    // The position of the element at `idx` when it's a symbol
+    pub fn sym_pos(&self, idx: usize) -> Option<SrcPos> {
+        self.sym_pos.as_ref()?.get(idx)?.clone()
+    }
+}
+
+// A call of `func` that an error propagated out of. `form` is the call
+// when it was evaluated directly rather than by apply.
+#[derive(Debug, Clone)]
+pub struct Frame {
+    pub func: MalVal,
+    pub form: Option<MalVal>,
+    pub pos: Option<SrcPos>,
+}
+
+impl Frame {
+    // The function's own name, else the symbol it was called by
+    pub fn name(&self) -> String {
+        match (&self.func, &self.form) {
+            (MalFunc { name: None, .. }, Some(List(l, _))) => match l.get(0) {
+                Some(Sym(s)) => s.clone(),
+                _ => self.func.fn_name(),
+            },
+            _ => self.func.fn_name(),
+        }
+    }
+
+    // {:fn name :form form :file file :line line :col col}, without the
+    // parts that aren't known
+    pub fn to_mal(&self) -> MalVal {
+        let mut kvs = vec![keyword("fn"), Str(self.name())];
+        if let Some(ref form) = self.form {
+            kvs.extend(vec![keyword("form"), form.clone()]);
+        }
+        if let Some(ref pos) = self.pos {
+            if let Some(ref file) = pos.file {
+                kvs.extend(vec![keyword("file"), Str(file.to_string())]);
+            }
+            kvs.extend(vec![keyword("line"), Int(pos.line as i64)]);
+            kvs.extend(vec![keyword("col"), Int(pos.col as i64)]);
+        }
+        hash_map(&kvs).unwrap_or(Nil)
+    }
+}
+
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())?;
+        if let Some(ref form) = self.form {
+            write!(f, ", called as {}", form.pr_str(true))?;
+        }
+        // like format_error, only positions in files are shown
+        if let Some(pos @ SrcPos { file: Some(_), .. }) = &self.pos {
+            write!(f, " at {}", pos)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+#[allow(clippy::enum_variant_names)]
 pub enum MalErr {
     ErrString(String),
     ErrMalVal(MalVal),
+    // raised by the interpreter, see err_info
+    ErrInfo(MalVal),
+    ErrAt(Box<MalErr>, SrcPos),
+    // the calls the error propagated out of, innermost first
+    ErrTrace(Box<MalErr>, Vec<Frame>),
 }
 
 pub type MalArgs = Vec<MalVal>;
+
+// The (params, body) of one of a function's arities
+pub type Arity = (MalVal, MalVal);
 pub type MalRet = Result<MalVal, MalErr>;
 
 // type utility macros
 
 macro_rules! list {
   ($seq:expr) => {{
-    List(Rc::new($seq),Rc::new(Nil))
+    List($seq,Rc::default())
   }};
   [$($args:expr),*] => {{
     let v: Vec<MalVal> = vec![$($args),*];
-    List(Rc::new(v),Rc::new(Nil))
+    List(v.into(),Rc::default())
   }}
 }
 
 macro_rules! vector {
   ($seq:expr) => {{
-    Vector(Rc::new($seq),Rc::new(Nil))
+    Vector($seq,Rc::default())
   }};
   [$($args:expr),*] => {{
     let v: Vec<MalVal> = vec![$($args),*];
-    Vector(Rc::new(v),Rc::new(Nil))
+    Vector(v.into(),Rc::default())
   }}
 }
 
@@ -68,13 +634,176 @@ pub fn error(s: &str) -> MalRet {
     Err(ErrString(s.to_string()))
 }
 
+// An error raised by the interpreter: a map with the kind of error as
+// :type (:arity, :type-error, :not-found, :reader...), a :message and
+// entries giving its context
+pub fn err_info(kind: &str, msg: &str, context: &[(&str, MalVal)]) -> MalErr {
+    let mut kvs = vec![keyword("type"), keyword(kind), keyword("message"), Str(msg.to_string())];
+    for (k, v) in context {
+        kvs.push(keyword(k));
+        kvs.push(v.clone());
+    }
+    ErrInfo(hash_map(&kvs).unwrap_or(Nil))
+}
+
+// The error for a call of `name` with the wrong number of args
+pub fn wrong_args(nargs: usize, name: &str) -> MalErr {
+    let msg = format!("wrong number of args ({}) passed to {}", nargs, name);
+    err_info("arity", &msg, &[("name", Str(name.to_string())), ("args", Int(nargs as i64))])
+}
+
+pub fn type_error(s: &str) -> MalRet {
+    Err(err_info("type-error", s, &[]))
+}
+
+// The error for a malformed special form or binding form
+pub fn syntax_error(s: &str) -> MalErr {
+    err_info("syntax", s, &[])
+}
+
+// The :message of an error map
+fn err_message(mv: &MalVal) -> Option<String> {
+    match mv {
+        Hash(hm, _) => match hm.get(&hash_key(&keyword("message")).ok()?) {
+            Some(Str(s)) => Some(s.clone()),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+// The :form of an error map, see MalErr::in_form
+fn err_form(mv: &MalVal) -> Option<&MalVal> {
+    match mv {
+        Hash(hm, _) => hm.get(&hash_key(&keyword("form")).ok()?),
+        _ => None,
+    }
+}
+
 pub fn format_error(e: MalErr) -> String {
     match e {
         ErrString(s) => s.clone(),
+        // a thrown ex-info shows its message followed by its data
+        ErrMalVal(ExInfo { message, data, .. }) => match *data {
+            Hash(ref hm, _) if hm.len() == 0 => message,
+            ref data => format!("{} {}", message, data.pr_str(true)),
+        },
         ErrMalVal(mv) => mv.pr_str(true),
+        ErrInfo(mv) => {
+            let msg = err_message(&mv).unwrap_or_default();
+            match err_form(&mv) {
+                Some(form) => format!("{} in {}", msg, form.pr_str(true)),
+                None => msg,
+            }
+        }
+        ErrAt(e, SrcPos { file: None, .. }) => format_error(*e),
+        ErrAt(e, pos) => format!("{}: {}", pos, format_error(*e)),
+        ErrTrace(e, _) => format_error(*e),
     }
 }
 
+impl MalErr {
+    // Record where the error happened. The innermost position wins so an
+    // error keeps the location of the form that raised it.
+    pub fn at(self, pos: Option<SrcPos>) -> MalErr {
+        match (self, pos) {
+            (ErrTrace(e, frames), pos) => ErrTrace(Box::new(e.at(pos)), frames),
+            (e @ ErrAt(..), _) | (e, None) => e,
+            (e, Some(p)) => ErrAt(Box::new(e), p),
+        }
+    }
+
+    // Record the form whose evaluation raised an interpreter error as its
+    // :form, unless it already has one. Errors thrown by the program, or
+    // that came out of a mal function or a loaded file, have their own
+    // context and are left as they are.
+    #[allow(dead_code)]
+    pub fn in_form(self, form: &MalVal) -> MalErr {
+        match self {
+            ErrString(s) => err_info("error", &s, &[("form", form.clone())]),
+            ErrInfo(Hash(hm, _)) if !hm.contains_key(&MapKey(keyword("form"))) => {
+                match _assoc(hm, &[keyword("form"), form.clone()]) {
+                    Ok(mv) => ErrInfo(mv),
+                    Err(e) => e,
+                }
+            }
+            ErrAt(e, pos @ SrcPos { file: None, .. }) => ErrAt(Box::new(e.in_form(form)), pos),
+            e => e,
+        }
+    }
+
+    // Record that the error propagated out of a call
+    pub fn traced(self, frame: Frame) -> MalErr {
+        match self {
+            ErrTrace(e, mut frames) => {
+                frames.push(frame);
+                ErrTrace(e, frames)
+            }
+            e => ErrTrace(Box::new(e), vec![frame]),
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn trace(&self) -> &[Frame] {
+        match self {
+            ErrTrace(_, frames) => frames,
+            _ => &[],
+        }
+    }
+
+    // The value bound by a catch* without a selector: the message of an
+    // interpreter error, or the thrown value
+    #[allow(dead_code)]
+    pub fn exc_val(&self) -> MalVal {
+        match self {
+            ErrString(s) => Str(s.clone()),
+            ErrInfo(mv) => Str(err_message(mv).unwrap_or_default()),
+            ErrMalVal(mv) => mv.clone(),
+            ErrAt(e, _) | ErrTrace(e, _) => e.exc_val(),
+        }
+    }
+
+    // The value bound by a catch* with a selector in stepA: a map with
+    // :type and :message for an interpreter error, or the thrown value
+    pub fn exc_info(&self) -> MalVal {
+        match self {
+            ErrString(s) => match err_info("error", s, &[]) {
+                ErrInfo(mv) => mv,
+                _ => Nil,
+            },
+            ErrInfo(mv) | ErrMalVal(mv) => mv.clone(),
+            ErrAt(e, _) | ErrTrace(e, _) => e.exc_info(),
+        }
+    }
+}
+
+// Integers that fit in an i64 are always represented as Int so that each
+// integer value has a single representation.
+pub fn big_int(b: num_bigint::BigInt) -> MalVal {
+    match b.to_i64() {
+        Some(i) => Int(i),
+        None => BigInt(Rc::new(b)),
+    }
+}
+
+thread_local! {
+    static KEYWORDS: RefCell<FnvHashMap<String, Rc<str>>> = RefCell::new(FnvHashMap::default());
+}
+
+// Keywords are interned so that each name is allocated once and
+// comparing two keywords is usually a pointer comparison.
+pub fn keyword(name: &str) -> MalVal {
+    KEYWORDS.with(|kws| {
+        let mut kws = kws.borrow_mut();
+        if let Some(k) = kws.get(name) {
+            return Keyword(k.clone());
+        }
+        let k: Rc<str> = Rc::from(name);
+        kws.insert(name.to_string(), k.clone());
+        Keyword(k)
+    })
+}
+
 pub fn atom(mv: &MalVal) -> MalVal {
     Atom(Rc::new(RefCell::new(mv.clone())))
 }
@@ -82,58 +811,99 @@ pub fn atom(mv: &MalVal) -> MalVal {
 impl MalVal {
     pub fn keyword(&self) -> MalRet {
         match self {
-            Str(s) if s.starts_with("\u{29e}") => Ok(Str(s.to_string())),
-            Str(s) => Ok(Str(format!("\u{29e}{}", s))),
-            _ => error("invalid type for keyword"),
+            Keyword(_) => Ok(self.clone()),
+            Str(s) => Ok(keyword(s)),
+            _ => type_error("invalid type for keyword"),
         }
     }
 
     pub fn empty_q(&self) -> MalRet {
         match self {
             List(l, _) | Vector(l, _) => Ok(Bool(l.len() == 0)),
+            Set(hs, _) => Ok(Bool(hs.len() == 0)),
             Nil => Ok(Bool(true)),
-            _ => error("invalid type for empty?"),
+            _ => type_error("invalid type for empty?"),
         }
     }
 
     pub fn count(&self) -> MalRet {
         match self {
             List(l, _) | Vector(l, _) => Ok(Int(l.len() as i64)),
+            Set(hs, _) => Ok(Int(hs.len() as i64)),
             Nil => Ok(Int(0)),
-            _ => error("invalid type for count"),
+            _ => type_error("invalid type for count"),
         }
     }
 
     pub fn apply(&self, args: MalArgs) -> MalRet {
         match *self {
             Func(f, _) => f(args),
+            MalFunc { eval, .. } => {
+                let (body, fn_env) = self.bind_call(args)?;
+                eval(body, fn_env).map_err(|e| {
+                    e.traced(Frame {
+                        func: self.clone(),
+                        form: None,
+                        pos: None,
+                    })
+                })
+            }
+            _ => type_error("attempt to call non-function"),
+        }
+    }
+
+    // The body to evaluate for a call of a function with `args`, and the
+    // environment to evaluate it in
+    pub fn bind_call(&self, args: MalArgs) -> Result<(MalVal, Env), MalErr> {
+        match self {
             MalFunc {
                 eval,
-                ref ast,
-                ref env,
-                ref params,
+                name,
+                arities,
+                env,
                 ..
             } => {
-                let a = &**ast;
-                let p = &**params;
-                let fn_env = env_bind(Some(env.clone()), p.clone(), args)?;
-                Ok(eval(a.clone(), fn_env)?)
+                // a single anonymous arity is checked by env_bind
+                let arity = if arities.len() == 1 && name.is_none() {
+                    Some(&arities[0])
+                } else {
+                    select_arity(arities, args.len())
+                };
+                let (params, body) = match arity {
+                    Some(arity) => arity,
+                    None => {
+                        return Err(wrong_args(args.len(), &self.fn_name()))
+                    }
+                };
+                let fn_env = env_bind(Some(env.clone()), params.clone(), args, *eval)?;
+                if let Some(name) = name {
+                    // the parameters can shadow the function's name
+                    env_sets_new(&fn_env, name, self.clone());
+                }
+                Ok((body.clone(), fn_env))
             }
-            _ => error("attempt to call non-function"),
+            _ => Err(err_info("type-error", "attempt to call non-function", &[])),
         }
     }
 
-    pub fn keyword_q(&self) -> bool {
+    // How a function is referred to in error messages: its name, or else
+    // its parameters
+    pub fn fn_name(&self) -> String {
         match self {
-            Str(s) if s.starts_with("\u{29e}") => true,
-            _ => false,
+            MalFunc {
+                name: Some(name), ..
+            } => name.to_string(),
+            MalFunc { arities, .. } => {
+                format!("fn {}", arities.iter().map(|(p, _)| p.pr_str(true)).join(" "))
+            }
+            _ => self.pr_str(true),
         }
     }
 
     pub fn deref(&self) -> MalRet {
         match self {
             Atom(a) => Ok(a.borrow().clone()),
-            _ => error("attempt to deref a non-Atom"),
+            _ => type_error("attempt to deref a non-Atom"),
         }
     }
 
@@ -143,29 +913,32 @@ impl MalVal {
                 *a.borrow_mut() = new.clone();
                 Ok(new.clone())
             }
-            _ => error("attempt to reset! a non-Atom"),
+            _ => type_error("attempt to reset! a non-Atom"),
         }
     }
 
-    pub fn swap_bang(&self, args: &MalArgs) -> MalRet {
+    pub fn swap_bang(&self, args: &[MalVal]) -> MalRet {
         match self {
             Atom(a) => {
                 let f = &args[0];
-                let mut fargs = args[1..].to_vec();
-                fargs.insert(0, a.borrow().clone());
+                let mut fargs = vec![a.borrow().clone()];
+                fargs.extend_from_slice(&args[1..]);
                 *a.borrow_mut() = f.apply(fargs)?;
                 Ok(a.borrow().clone())
             }
-            _ => error("attempt to swap! a non-Atom"),
+            _ => type_error("attempt to swap! a non-Atom"),
         }
     }
 
     pub fn get_meta(&self) -> MalRet {
         match self {
-            List(_, meta) | Vector(_, meta) | Hash(_, meta) => Ok((&**meta).clone()),
+            List(_, meta) | Vector(_, meta) | Hash(_, meta) | Set(_, meta) => {
+                Ok(meta.val.clone())
+            }
+            ExInfo { meta, .. } => Ok(meta.val.clone()),
             Func(_, meta) => Ok((&**meta).clone()),
             MalFunc { meta, .. } => Ok((&**meta).clone()),
-            _ => error("meta not supported by type"),
+            _ => type_error("meta not supported by type"),
         }
     }
 
@@ -174,14 +947,79 @@ impl MalVal {
             List(_, ref mut meta)
             | Vector(_, ref mut meta)
             | Hash(_, ref mut meta)
-            | Func(_, ref mut meta)
-            | MalFunc { ref mut meta, .. } => {
+            | Set(_, ref mut meta)
+            | ExInfo { ref mut meta, .. } => {
+                *meta = Rc::new(Meta {
+                    val: new_meta.clone(),
+                    ..(**meta).clone()
+                });
+            }
+            Func(_, ref mut meta) | MalFunc { ref mut meta, .. } => {
                 *meta = Rc::new((&*new_meta).clone());
             }
-            _ => return error("with-meta not supported by type"),
+            _ => return type_error("with-meta not supported by type"),
         };
         Ok(self.clone())
     }
+
+    pub fn with_pos(mut self, pos: SrcPos) -> MalVal {
+        if let List(_, ref mut meta)
+        | Vector(_, ref mut meta)
+        | Hash(_, ref mut meta)
+        | Set(_, ref mut meta) = self
+        {
+            *meta = Rc::new(Meta {
+                pos: Some(pos),
+                ..(**meta).clone()
+            });
+        }
+        self
+    }
+
+    // Keep the error that catch* caught the value from, when the value can
+    // carry it
+    #[allow(dead_code)]
+    pub fn with_caught(mut self, e: Rc<MalErr>) -> MalVal {
+        if let List(_, ref mut meta)
+        | Vector(_, ref mut meta)
+        | Hash(_, ref mut meta)
+        | Set(_, ref mut meta)
+        | ExInfo { ref mut meta, .. } = self
+        {
+            *meta = Rc::new(Meta {
+                caught: Some(e),
+                ..(**meta).clone()
+            });
+        }
+        self
+    }
+
+    // The error that catch* caught the value from
+    pub fn caught(&self) -> Option<&MalErr> {
+        match self {
+            List(_, meta)
+            | Vector(_, meta)
+            | Hash(_, meta)
+            | Set(_, meta)
+            | ExInfo { meta, .. } => meta.caught.as_deref(),
+            _ => None,
+        }
+    }
+
+    // Record the positions of the symbols in a list or vector, given in
+    // the order of its elements
+    pub fn with_sym_pos(mut self, sym_pos: Vec<Option<SrcPos>>) -> MalVal {
+        if sym_pos.iter().all(Option::is_none) {
+            return self;
+        }
+        if let List(_, ref mut meta) | Vector(_, ref mut meta) = self {
+            *meta = Rc::new(Meta {
+                sym_pos: Some(Rc::new(sym_pos)),
+                ..(**meta).clone()
+            });
+        }
+        self
+    }
 }
 
 impl PartialEq for MalVal {
@@ -190,51 +1028,84 @@ impl PartialEq for MalVal {
             (Nil, Nil) => true,
             (Bool(ref a), Bool(ref b)) => a == b,
             (Int(ref a), Int(ref b)) => a == b,
+            (BigInt(ref a), BigInt(ref b)) => a == b,
+            (Int(a), BigInt(ref b)) | (BigInt(ref b), Int(a)) => {
+                **b == num_bigint::BigInt::from(*a)
+            }
+            (Float(ref a), Float(ref b)) => a == b,
             (Str(ref a), Str(ref b)) => a == b,
+            (Char(a), Char(b)) => a == b,
+            (Keyword(ref a), Keyword(ref b)) => a == b,
             (Sym(ref a), Sym(ref b)) => a == b,
             (List(ref a, _), List(ref b, _))
             | (Vector(ref a, _), Vector(ref b, _))
             | (List(ref a, _), Vector(ref b, _))
             | (Vector(ref a, _), List(ref b, _)) => a == b,
             (Hash(ref a, _), Hash(ref b, _)) => a == b,
+            (Set(ref a, _), Set(ref b, _)) => a == b,
+            (
+                ExInfo { message: a, data: a_data, .. },
+                ExInfo { message: b, data: b_data, .. },
+            ) => a == b && a_data == b_data,
             (MalFunc { .. }, MalFunc { .. }) => false,
             _ => false,
         }
     }
 }
 
+// The (params, body) of the arity that takes `nargs` arguments: the one
+// with exactly that many parameters, or else the variadic one
+fn select_arity(arities: &[Arity], nargs: usize) -> Option<&Arity> {
+    let mut variadic = None;
+    for arity in arities {
+        match fn_arity(&arity.0) {
+            Some((n, false)) if n == nargs => return Some(arity),
+            Some((n, true)) if n <= nargs => variadic = Some(arity),
+            _ => (),
+        }
+    }
+    variadic
+}
+
 pub fn func(f: fn(MalArgs) -> MalRet) -> MalVal {
     Func(f, Rc::new(Nil))
 }
 
-pub fn _assoc(mut hm: FnvHashMap<String, MalVal>, kvs: MalArgs) -> MalRet {
+pub fn _assoc(mut hm: MalMap, kvs: &[MalVal]) -> MalRet {
     if kvs.len() % 2 != 0 {
         return error("odd number of elements");
     }
     for (k, v) in kvs.iter().tuples() {
-        match k {
-            Str(s) => {
-                hm.insert(s.to_string(), v.clone());
-            }
-            _ => return error("key is not string"),
-        }
+        hm.insert(hash_key(k)?, v.clone());
     }
-    Ok(Hash(Rc::new(hm), Rc::new(Nil)))
+    Ok(Hash(hm, Rc::default()))
 }
 
-pub fn _dissoc(mut hm: FnvHashMap<String, MalVal>, ks: MalArgs) -> MalRet {
+pub fn _dissoc(mut hm: MalMap, ks: &[MalVal]) -> MalRet {
     for k in ks.iter() {
-        match k {
-            Str(ref s) => {
-                hm.remove(s);
-            }
-            _ => return error("key is not string"),
-        }
+        hm.remove(&hash_key(k)?);
+    }
+    Ok(Hash(hm, Rc::default()))
+}
+
+pub fn hash_map(kvs: &[MalVal]) -> MalRet {
+    _assoc(MalMap::default(), kvs)
+}
+
+pub fn _conj_set(mut hs: MalMap, items: &[MalVal]) -> MalRet {
+    for mv in items.iter() {
+        hs.insert(hash_key(mv)?, mv.clone());
+    }
+    Ok(Set(hs, Rc::default()))
+}
+
+pub fn _disj(mut hs: MalMap, items: &[MalVal]) -> MalRet {
+    for mv in items.iter() {
+        hs.remove(&hash_key(mv)?);
     }
-    Ok(Hash(Rc::new(hm), Rc::new(Nil)))
+    Ok(Set(hs, Rc::default()))
 }
 
-pub fn hash_map(kvs: MalArgs) -> MalRet {
-    let hm: FnvHashMap<String, MalVal> = FnvHashMap::default();
-    _assoc(hm, kvs)
+pub fn hash_set(items: &[MalVal]) -> MalRet {
+    _conj_set(MalMap::default(), items)
 }